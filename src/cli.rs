@@ -1,15 +1,142 @@
 use getopts::Options;
 
+use metatape::{EofPolicy, IOMode, MinifyOptions, ParseOptions, PartialOutputPolicy, SourceFormat};
+
 pub fn get_opts() -> Options {
     let mut opts = Options::new();
     opts.optflag("h", "help", "print this help text");
     opts.optflag("v", "verbose", "print debug info on each instruction");
+    opts.optflag(
+        "d",
+        "debug",
+        "run the program in an interactive debugger (type `help` at the prompt for commands)",
+    );
+    opts.optflag(
+        "u",
+        "unbuffered",
+        "write each unit of output immediately instead of waiting for a newline",
+    );
+    opts.optopt(
+        "",
+        "format",
+        "source format of the program (detected automatically by default)",
+        "ascii|golf",
+    );
+    opts.optflag(
+        "",
+        "strict",
+        "warn about mixed-case instructions and code that is probably a mistake",
+    );
+    opts.optflag(
+        "",
+        "allow-undefined",
+        "allow calls to undefined subroutines (they fail when they are run)",
+    );
+    opts.optopt(
+        "",
+        "error-format",
+        "how to print parse errors and warnings (default human)",
+        "human|json",
+    );
+    opts.optflag(
+        "",
+        "check",
+        "check the program for errors and warnings without running it (with `fmt`, check \
+         that the file is already formatted)",
+    );
+    opts.optopt(
+        "",
+        "io-mode",
+        "initial I/O mode (default msb)",
+        "msb|lsb|ascii|utf8",
+    );
+    opts.optopt(
+        "",
+        "eof",
+        "what to do when reading past the end of the input (default zero)",
+        "zero|halt|error|flag",
+    );
+    opts.optopt(
+        "",
+        "partial-output",
+        "what to do if the program ends partway through a unit of output (default warn)",
+        "pad|warn|error",
+    );
+    opts.optopt(
+        "",
+        "seed",
+        "seed for the random bits generated by `?` (chosen randomly by default)",
+        "SEED",
+    );
+    opts.optopt(
+        "",
+        "random-bits",
+        "file containing the bits generated by `?`, as 0s and 1s",
+        "FILE",
+    );
+    opts.optopt(
+        "",
+        "save-on-halt",
+        "at each `h`, save the state of the program to FILE",
+        "FILE",
+    );
+    opts.optopt(
+        "",
+        "resume",
+        "resume the program from a state saved by `--save-on-halt`",
+        "FILE",
+    );
+    opts.optopt(
+        "",
+        "dot",
+        "when the program ends, write the tape to FILE as a Graphviz graph",
+        "FILE",
+    );
+    opts.optflag(
+        "",
+        "inline",
+        "with `minify`, replace calls to subroutines that are only called once with their bodies",
+    );
+    opts.optflag(
+        "",
+        "rename",
+        "with `minify`, rename subroutines to single characters",
+    );
+    opts.optflag(
+        "",
+        "encode",
+        "write the golf encoding of the program to stdout instead of running it",
+    );
     opts
 }
 
+/// What to do with the program.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Command {
+    Run,
+    Fmt,
+    Minify,
+}
+
 pub struct Config {
     pub filename: String,
+    pub command: Command,
     pub verbose: bool,
+    pub debug: bool,
+    pub unbuffered: bool,
+    pub parse_options: ParseOptions,
+    pub json_errors: bool,
+    pub check: bool,
+    pub io_mode: IOMode,
+    pub eof_policy: EofPolicy,
+    pub partial_output_policy: PartialOutputPolicy,
+    pub seed: Option<u64>,
+    pub random_bits: Option<String>,
+    pub save_on_halt: Option<String>,
+    pub resume: Option<String>,
+    pub dot: Option<String>,
+    pub encode: bool,
+    pub minify_options: MinifyOptions,
 }
 
 pub fn get_config() -> Result<Config, ()> {
@@ -18,9 +145,59 @@ pub fn get_config() -> Result<Config, ()> {
         if matches.opt_present("h") {
             Err(())
         } else {
+            let command = match matches.free.len() {
+                1 => Command::Run,
+                2 if matches.free[0] == "fmt" => Command::Fmt,
+                2 if matches.free[0] == "minify" => Command::Minify,
+                _ => return Err(()),
+            };
             Ok(Config {
                 filename: matches.free.pop().ok_or(())?,
+                command,
                 verbose: matches.opt_present("v"),
+                debug: matches.opt_present("d"),
+                unbuffered: matches.opt_present("u"),
+                parse_options: ParseOptions {
+                    format: match matches.opt_str("format").as_deref() {
+                        None => None,
+                        Some("ascii") => Some(SourceFormat::Ascii),
+                        Some("golf") => Some(SourceFormat::Golf),
+                        Some(_) => return Err(()),
+                    },
+                    strict: matches.opt_present("strict"),
+                    allow_undefined_calls: matches.opt_present("allow-undefined"),
+                },
+                json_errors: match matches.opt_str("error-format").as_deref() {
+                    None | Some("human") => false,
+                    Some("json") => true,
+                    Some(_) => return Err(()),
+                },
+                check: matches.opt_present("check"),
+                io_mode: match matches.opt_str("io-mode") {
+                    None => IOMode::default(),
+                    Some(s) => s.parse().map_err(|_| ())?,
+                },
+                eof_policy: match matches.opt_str("eof") {
+                    None => EofPolicy::default(),
+                    Some(s) => s.parse().map_err(|_| ())?,
+                },
+                partial_output_policy: match matches.opt_str("partial-output") {
+                    None => PartialOutputPolicy::default(),
+                    Some(s) => s.parse().map_err(|_| ())?,
+                },
+                seed: match matches.opt_str("seed") {
+                    None => None,
+                    Some(s) => Some(s.parse().map_err(|_| ())?),
+                },
+                random_bits: matches.opt_str("random-bits"),
+                save_on_halt: matches.opt_str("save-on-halt"),
+                resume: matches.opt_str("resume"),
+                dot: matches.opt_str("dot"),
+                encode: matches.opt_present("encode"),
+                minify_options: MinifyOptions {
+                    inline: matches.opt_present("inline"),
+                    rename: matches.opt_present("rename"),
+                },
             })
         }
     } else {
@@ -30,7 +207,13 @@ pub fn get_config() -> Result<Config, ()> {
 
 pub fn print_usage() {
     let program = std::env::args().next().unwrap();
-    let brief = format!("Usage: {} [options] <filename>", program);
+    let brief = format!(
+        "Usage: {0} [options] <filename>\n       {0} fmt [--check] <filename>\n       \
+         {0} minify [--inline] [--rename] <filename>\n\n\
+         `fmt` writes the formatted source code of a program to stdout. `minify` writes the \
+         shortest equivalent source code to stdout and its length to stderr.",
+        program,
+    );
     println!(
         "Metatape v{}\n\n{}",
         env!("CARGO_PKG_VERSION"),
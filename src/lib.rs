@@ -0,0 +1,16 @@
+//! Interpreter for Metatape, an esoteric programming language with just two
+//! data types: null and tape.
+//!
+//! Parse a program using `parse_str()` or `program_from_file()`, and then run
+//! it using a `Runtime`, which can be configured using its `with_*()` methods
+//! before calling `step()` repeatedly.
+
+// Parse errors are large, but parsing is not performance-sensitive.
+#![allow(clippy::result_large_err)]
+
+#[macro_use]
+extern crate pest_derive;
+
+mod metatape;
+
+pub use metatape::*;
@@ -1,8 +1,6 @@
-#[macro_use]
-extern crate pest_derive;
+use std::io::Write;
 
 mod cli;
-mod metatape;
 
 fn main() {
     let config = cli::get_config().unwrap_or_else(|_| {
@@ -10,16 +8,134 @@ fn main() {
         std::process::exit(0);
     });
 
-    let cli::Config { filename, verbose } = config;
+    let cli::Config {
+        filename,
+        command,
+        verbose,
+        debug,
+        unbuffered,
+        parse_options,
+        json_errors,
+        check,
+        io_mode,
+        eof_policy,
+        partial_output_policy,
+        seed,
+        random_bits,
+        save_on_halt,
+        resume,
+        dot,
+        encode,
+        minify_options,
+    } = config;
 
-    let mut runtime = metatape::runtime_from_file(&filename).unwrap_or_else(|error_msg| {
-        println!("{}", error_msg);
+    if command == cli::Command::Fmt {
+        format_file(&filename, check, json_errors);
+        return;
+    }
+
+    let program = metatape::program_from_file(&filename, &parse_options).unwrap_or_else(|errors| {
+        for error in &errors {
+            if json_errors {
+                eprintln!("{}", error.to_json("error"));
+            } else {
+                println!("{}\n", error);
+            }
+        }
         std::process::exit(1);
     });
+    for warning in &program.warnings {
+        if json_errors {
+            eprintln!("{}", warning.to_json("warning"));
+        } else {
+            eprintln!("warning:\n{}", warning);
+        }
+    }
+    if check {
+        return;
+    }
+    if encode {
+        let bytes = metatape::golf_encode(&program);
+        // We don't care whether the write actually succeeds.
+        let _ = std::io::stdout().write_all(&bytes);
+        eprintln!("{} bytes", bytes.len());
+        return;
+    }
+    if command == cli::Command::Minify {
+        let minified = metatape::minify(&program, &minify_options);
+        println!("{}", minified);
+        let chars = minified.chars().count();
+        match metatape::parse_str_with_options(&minified, &parse_options) {
+            Ok(minified_program) => eprintln!(
+                "{} chars; {} bytes using golf encoding",
+                chars,
+                metatape::golf_encode(&minified_program).len(),
+            ),
+            Err(_) => eprintln!("{} chars", chars),
+        }
+        return;
+    }
+
+    let mut runtime = metatape::Runtime::new(program)
+        .with_io_mode(io_mode)
+        .with_eof_policy(eof_policy)
+        .with_partial_output_policy(partial_output_policy)
+        .with_buffered_output(!unbuffered);
+    // Always use a seed, so that any run can be replayed.
+    let seed = seed.unwrap_or_else(rand::random);
+    match &random_bits {
+        Some(filename) => {
+            let bits = std::fs::read_to_string(filename)
+                .map_err(|err| err.to_string())
+                .and_then(|s| s.parse::<metatape::BitSequence>())
+                .unwrap_or_else(|error_msg| {
+                    println!("{}", error_msg);
+                    std::process::exit(1);
+                });
+            runtime.set_random_source(bits);
+        }
+        None => runtime.set_seed(seed),
+    }
+    if let Some(filename) = &resume {
+        let loaded = std::fs::read_to_string(filename)
+            .map_err(|err| err.to_string())
+            .and_then(|saved| runtime.load(&saved));
+        if let Err(error_msg) = loaded {
+            println!("Could not resume from {}: {}", filename, error_msg);
+            std::process::exit(1);
+        }
+    }
     if verbose {
-        runtime.set_output_fn(Box::new(|byte| {
-            println!("Output byte {:#02x}: {:#?}", byte, byte as char);
-        }))
+        // Keep output interleaved with debug info.
+        runtime.set_output(VerboseOutput);
+        runtime.set_buffered_output(false);
+        if random_bits.is_none() {
+            println!("Random seed: {}", seed);
+        }
+    }
+
+    if debug {
+        // Keep output interleaved with the debugger's output.
+        runtime.set_buffered_output(false);
+        let mut debugger = metatape::Debugger::new(runtime);
+        // Read one line at a time, so that any input after the command is
+        // left for the program.
+        let commands = std::iter::from_fn(|| {
+            let mut line = String::new();
+            match std::io::stdin().read_line(&mut line) {
+                Ok(0) | Err(_) => None,
+                Ok(_) => Some(line),
+            }
+        });
+        let result = debugger.run(commands, &mut std::io::stdout());
+        runtime = debugger.into_runtime();
+        if let Some(filename) = &dot {
+            write_file(filename, runtime.get_head().to_dot());
+        }
+        match result {
+            Ok(Some(error)) => return report_exit(&runtime, error, false, seed, &random_bits),
+            Ok(None) | Err(_) => return,
+        }
     }
 
     let mut result = Ok(());
@@ -31,10 +147,104 @@ fn main() {
         }
         if let Err(metatape::RuntimeError::Halt) = result {
             println!("HALT");
+            if verbose {
+                for line in runtime.backtrace() {
+                    println!("    {}", line);
+                }
+            }
             result = runtime.unhalt();
+            if let Some(filename) = &save_on_halt {
+                write_file(filename, runtime.save());
+            }
         }
     }
+    let error = result.unwrap_err();
     if verbose {
-        println!("Program exited because {:?}", result.unwrap_err());
+        println!("Program exited because {:?}", error);
+    }
+    if let Some(filename) = &dot {
+        write_file(filename, runtime.get_head().to_dot());
+    }
+    report_exit(&runtime, error, !verbose, seed, &random_bits);
+}
+
+/// Writes a file, printing an error if it can't be written.
+fn write_file(filename: &str, contents: String) {
+    if let Err(error) = std::fs::write(filename, contents) {
+        eprintln!("error: could not write {}: {}", filename, error);
+    }
+}
+
+/// Reports how the program ended, exiting with an error if it did not end
+/// normally. `print_error` is false if the error has already been printed.
+fn report_exit(
+    runtime: &metatape::Runtime,
+    error: metatape::RuntimeError,
+    print_error: bool,
+    seed: u64,
+    random_bits: &Option<String>,
+) {
+    match error {
+        metatape::RuntimeError::EndOfProgram => {
+            let pending_bits = runtime.pending_output_bits();
+            if !pending_bits.is_empty() {
+                let bits: String = pending_bits
+                    .iter()
+                    .map(|&b| if b { '1' } else { '0' })
+                    .collect();
+                eprintln!("warning: program ended with partial output {:?}", bits);
+            }
+        }
+        _ => {
+            if print_error {
+                eprintln!("error: {:?}", error);
+            }
+            for line in runtime.backtrace() {
+                eprintln!("    {}", line);
+            }
+            if random_bits.is_none() && runtime.used_random_bits() {
+                eprintln!("random seed was {}", seed);
+            }
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Output that prints a description of each byte, for verbose mode.
+struct VerboseOutput;
+
+impl Write for VerboseOutput {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        for &byte in buf {
+            println!("Output byte {:#02x}: {:#?}", byte, byte as char);
+        }
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Writes the formatted source code of a file to stdout, or with `check`,
+/// exits with an error if the file is not already formatted.
+fn format_file(filename: &str, check: bool, json_errors: bool) {
+    let source = std::fs::read_to_string(filename).unwrap_or_else(|err| {
+        eprintln!("error: unable to read {:?}: {}", filename, err);
+        std::process::exit(1);
+    });
+    let formatted = metatape::format_source(&source).unwrap_or_else(|error| {
+        let error = error.with_path(filename);
+        if json_errors {
+            eprintln!("{}", error.to_json("error"));
+        } else {
+            println!("{}\n", error);
+        }
+        std::process::exit(1);
+    });
+    if !check {
+        print!("{}", formatted);
+    } else if formatted != source {
+        eprintln!("{} is not formatted", filename);
+        std::process::exit(1);
     }
 }
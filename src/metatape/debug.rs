@@ -2,12 +2,19 @@ use super::{Runtime, RuntimeError};
 
 impl Runtime {
     pub fn debug_step(&mut self) -> Result<(), RuntimeError> {
-        let (current_instruction_str_idx, current_instruction) = self.fetch_instruction()?;
-        let (row, col) =
-            pest::Position::new(&self.get_program().source, *current_instruction_str_idx)
-                .unwrap()
-                .line_col();
-        let s = format!(
+        let (current_instruction_str_idx, current_instruction) = match self.fetch_instruction() {
+            Ok(instruction) => instruction,
+            // Let `step()` handle the end of the program.
+            Err(_) => return self.step().map(|_| ()),
+        };
+        let (file, row, col) = self.get_program().locate(*current_instruction_str_idx);
+        let mut s = String::new();
+        // Only name the file for instructions that come from loaded files.
+        if file.start != 0 {
+            s.push_str(file.path.as_deref().unwrap_or_default());
+            s.push(':');
+        }
+        s += &format!(
             "{row:>5}:{col:<5}{ip:>3} {instruction:<14}",
             row = row,
             col = col,
@@ -0,0 +1,531 @@
+//! Interactive debugger with breakpoints and reverse execution.
+
+use std::collections::HashSet;
+use std::io::{self, Write};
+use std::rc::Rc;
+
+use super::history::History;
+use super::program::{Instruction, InstructionBlock, InstructionSeq};
+use super::{RenderOptions, Runtime, RuntimeError};
+
+const HELP: &str = "\
+Commands:
+  s, step          execute one instruction
+  n, next          execute one instruction, without stopping inside subroutines, blocks, or forks
+  c, continue      run until a breakpoint, `h`, or the end of the program
+  f, finish        run until the current subroutine, block, or fork returns
+  b, break [LOC]   set a breakpoint at LINE or LINE:COL in the main file, or at the start of
+                   the subroutine named LOC; with no LOC, list breakpoints
+  d, delete N      delete breakpoint N
+  rs, reverse-step undo one instruction
+  rc, reverse-continue
+                   undo instructions until a breakpoint or the start of the history
+  lc, last-change  undo instructions until just before the current cell last changed
+  lr, last-random  undo instructions until just before the last `?`
+  t, tape [DEPTH]  print the current row of the tape; with DEPTH, print DEPTH levels of parent
+                   and child tapes as nested brackets, with `*` before the current cell
+  w, where         print the current instruction
+  bt, backtrace    print the subroutines, blocks, and forks that are executing
+  q, quit          stop debugging
+  h, help          print this help text
+An empty line repeats the last command.";
+
+enum Breakpoint {
+    /// Breakpoint before any of the instructions at these positions in the
+    /// program's source.
+    Location {
+        description: String,
+        source_indices: HashSet<usize>,
+    },
+    /// Breakpoint at the start of a subroutine.
+    Subroutine {
+        name: String,
+        block: InstructionBlock,
+    },
+}
+
+/// Reason that the debugger stopped running the program.
+enum Stop {
+    /// The command finished.
+    Done,
+    Breakpoint(usize),
+    Halt,
+    /// The program ended, or there was an error.
+    Exit(RuntimeError),
+    /// There are no more instructions to undo.
+    StartOfHistory,
+}
+
+/// Interactive debugger that runs a program one command at a time.
+pub struct Debugger {
+    runtime: Runtime,
+    breakpoints: Vec<Option<Breakpoint>>,
+    /// Whether the program has stopped at `h`.
+    halted: bool,
+    last_command: String,
+    history: History,
+}
+
+impl Debugger {
+    /// Creates a debugger. The runtime is made replayable, so that the
+    /// program reads the same input and random bits after going backwards.
+    pub fn new(mut runtime: Runtime) -> Self {
+        runtime.set_replayable(true);
+        Self {
+            runtime,
+            breakpoints: vec![],
+            halted: false,
+            last_command: String::new(),
+            history: History::default(),
+        }
+    }
+
+    pub fn get_runtime(&self) -> &Runtime {
+        &self.runtime
+    }
+
+    pub fn into_runtime(self) -> Runtime {
+        self.runtime
+    }
+
+    /// Runs commands from `commands`, writing prompts and results to
+    /// `output`. Returns the error that ended the program (which is
+    /// `RuntimeError::EndOfProgram` if it ended normally), or `None` if the
+    /// user quit or there were no more commands.
+    pub fn run(
+        &mut self,
+        mut commands: impl Iterator<Item = String>,
+        output: &mut impl Write,
+    ) -> io::Result<Option<RuntimeError>> {
+        self.print_location(output)?;
+        loop {
+            write!(output, "(metatape) ")?;
+            output.flush()?;
+            let command = match commands.next() {
+                Some(command) => command,
+                None => {
+                    writeln!(output)?;
+                    return Ok(None);
+                }
+            };
+            let command = match command.trim() {
+                "" => self.last_command.clone(),
+                command => command.to_owned(),
+            };
+            self.last_command = command.clone();
+            let mut words = command.split_whitespace();
+            let name = words.next().unwrap_or_default();
+            let arg = words.collect::<Vec<_>>().join(" ");
+            let stop = match name {
+                "" => continue,
+                "s" | "step" => self.resume(|_| true),
+                "n" | "next" => {
+                    let depth = self.runtime.call_depth();
+                    self.resume(move |runtime| runtime.call_depth() <= depth)
+                }
+                "c" | "continue" => self.resume(|_| false),
+                "f" | "finish" => match self.runtime.call_depth() {
+                    0 => {
+                        writeln!(output, "Not in a subroutine, block, or fork")?;
+                        continue;
+                    }
+                    depth => self.resume(move |runtime| runtime.call_depth() < depth),
+                },
+                "b" | "break" if arg.is_empty() => {
+                    self.print_breakpoints(output)?;
+                    continue;
+                }
+                "b" | "break" => {
+                    match self.add_breakpoint(&arg) {
+                        Ok(idx) => writeln!(output, "Breakpoint {} at {}", idx, arg)?,
+                        Err(message) => writeln!(output, "{}", message)?,
+                    }
+                    continue;
+                }
+                "d" | "delete" => {
+                    match arg.parse().ok().and_then(|idx: usize| {
+                        self.breakpoints.get_mut(idx.checked_sub(1)?)?.take()
+                    }) {
+                        Some(_) => writeln!(output, "Deleted breakpoint {}", arg)?,
+                        None => writeln!(output, "No breakpoint {:?}", arg)?,
+                    }
+                    continue;
+                }
+                "rs" | "reverse-step" => self.rewind_to(self.history.len().saturating_sub(1)),
+                "rc" | "reverse-continue" => {
+                    // Without a breakpoint, go back to the start of the history.
+                    let idx = self.history.rposition(|snapshot| {
+                        self.breakpoint_at(&snapshot.executing_block, snapshot.instruction_pointer)
+                            .is_some()
+                    });
+                    self.rewind_to(idx.unwrap_or(0))
+                }
+                "lc" | "last-change" => match self.history.last_change(&self.runtime.snapshot()) {
+                    Some(idx) => self.rewind_to(idx),
+                    None => {
+                        writeln!(
+                            output,
+                            "The current cell has not changed since the start of the history"
+                        )?;
+                        continue;
+                    }
+                },
+                "lr" | "last-random" => match self.history.rposition(|snapshot| {
+                    matches!(snapshot.instruction(), Some((_, Instruction::Random)))
+                }) {
+                    Some(idx) => self.rewind_to(idx),
+                    None => {
+                        writeln!(
+                            output,
+                            "No `?` has been executed since the start of the history"
+                        )?;
+                        continue;
+                    }
+                },
+                "t" | "tape" if arg.is_empty() => {
+                    writeln!(output, "{:#}", self.runtime.get_head())?;
+                    continue;
+                }
+                "t" | "tape" => {
+                    match arg.parse() {
+                        Ok(depth) => {
+                            let options = RenderOptions {
+                                parents: depth,
+                                children: depth,
+                            };
+                            writeln!(output, "{}", self.runtime.get_head().render(&options))?;
+                        }
+                        Err(_) => writeln!(output, "Invalid depth {:?}", arg)?,
+                    }
+                    continue;
+                }
+                "w" | "where" => {
+                    self.print_location(output)?;
+                    continue;
+                }
+                "bt" | "backtrace" => {
+                    for line in self.runtime.backtrace() {
+                        writeln!(output, "{}", line)?;
+                    }
+                    continue;
+                }
+                "q" | "quit" => return Ok(None),
+                "h" | "help" => {
+                    writeln!(output, "{}", HELP)?;
+                    continue;
+                }
+                _ => {
+                    writeln!(output, "Unknown command {:?}; type `help` for a list", name)?;
+                    continue;
+                }
+            };
+            match stop {
+                Stop::Done => (),
+                Stop::StartOfHistory => writeln!(output, "No earlier instructions were recorded")?,
+                Stop::Breakpoint(idx) => writeln!(output, "Breakpoint {}", idx + 1)?,
+                Stop::Halt => writeln!(output, "Halted")?,
+                Stop::Exit(error) => {
+                    writeln!(output, "Program exited because {:?}", error)?;
+                    return Ok(Some(error));
+                }
+            }
+            self.print_location(output)?;
+        }
+    }
+
+    /// Executes instructions until `done` returns true after an instruction,
+    /// or until the program stops for some other reason.
+    fn resume(&mut self, done: impl Fn(&Runtime) -> bool) -> Stop {
+        loop {
+            self.history.push(self.runtime.snapshot(), self.halted);
+            let result = if self.halted {
+                self.halted = false;
+                self.runtime.unhalt()
+            } else {
+                self.runtime.step().map(|_| ())
+            };
+            match result {
+                Ok(()) => (),
+                Err(RuntimeError::Halt) => {
+                    self.halted = true;
+                    return Stop::Halt;
+                }
+                Err(error) => return Stop::Exit(error),
+            }
+            if done(&self.runtime) {
+                return Stop::Done;
+            }
+            if let Some(idx) = self.breakpoint_hit() {
+                return Stop::Breakpoint(idx);
+            }
+        }
+    }
+
+    /// Goes back to the state with the given index in the history, or
+    /// returns `Stop::StartOfHistory` if there is none.
+    fn rewind_to(&mut self, idx: usize) -> Stop {
+        match self.history.rewind(idx) {
+            Some(entry) => {
+                self.runtime.restore(&entry.snapshot);
+                self.halted = entry.halted;
+                Stop::Done
+            }
+            None => Stop::StartOfHistory,
+        }
+    }
+
+    /// Returns the index of a breakpoint at the current instruction, if
+    /// there is one.
+    fn breakpoint_hit(&self) -> Option<usize> {
+        self.breakpoint_at(
+            self.runtime.get_executing_block(),
+            self.runtime.get_instruction_pointer(),
+        )
+    }
+
+    /// Returns the index of a breakpoint at an instruction, if there is one.
+    fn breakpoint_at(&self, block: &InstructionBlock, instruction_pointer: usize) -> Option<usize> {
+        let (source_idx, _) = block.get(instruction_pointer)?;
+        self.breakpoints
+            .iter()
+            .position(|breakpoint| match breakpoint {
+                Some(Breakpoint::Location { source_indices, .. }) => {
+                    source_indices.contains(source_idx)
+                }
+                Some(Breakpoint::Subroutine {
+                    block: subroutine, ..
+                }) => instruction_pointer == 0 && Rc::ptr_eq(block, subroutine),
+                None => false,
+            })
+    }
+
+    /// Adds a breakpoint, returning its number.
+    fn add_breakpoint(&mut self, location: &str) -> Result<usize, String> {
+        let program = self.runtime.get_program();
+        let breakpoint = if let Some(block) = program.subroutines.get(location) {
+            Breakpoint::Subroutine {
+                name: location.to_owned(),
+                block: block.clone(),
+            }
+        } else {
+            let mut parts = location.splitn(2, ':').map(str::parse::<usize>);
+            let line = match parts.next() {
+                Some(Ok(line)) => line,
+                _ => return Err(format!("No subroutine named {:?}", location)),
+            };
+            let col = match parts.next() {
+                Some(Ok(col)) => Some(col),
+                Some(Err(_)) => return Err(format!("Invalid location {:?}", location)),
+                None => None,
+            };
+            // Find the instructions at that position in the main file.
+            let mut positions = vec![];
+            collect_positions(&mut positions, &program.instructions);
+            for subroutine in program.subroutines.values() {
+                collect_positions(&mut positions, subroutine);
+            }
+            let mut matches: Vec<(usize, usize)> = positions
+                .into_iter()
+                .filter_map(|source_idx| match program.locate(source_idx) {
+                    (file, l, c) if file.start == 0 && l == line => Some((c, source_idx)),
+                    _ => None,
+                })
+                .filter(|&(c, _)| col.unwrap_or(c) == c)
+                .collect();
+            // Only stop at the start of the line.
+            let first_col = matches.iter().map(|&(c, _)| c).min();
+            matches.retain(|&(c, _)| Some(c) == first_col);
+            if matches.is_empty() {
+                return Err(format!("No instruction at {}", location));
+            }
+            Breakpoint::Location {
+                description: location.to_owned(),
+                source_indices: matches
+                    .into_iter()
+                    .map(|(_, source_idx)| source_idx)
+                    .collect(),
+            }
+        };
+        self.breakpoints.push(Some(breakpoint));
+        Ok(self.breakpoints.len())
+    }
+
+    fn print_breakpoints(&self, output: &mut impl Write) -> io::Result<()> {
+        let mut any = false;
+        for (idx, breakpoint) in self.breakpoints.iter().enumerate() {
+            let description = match breakpoint {
+                Some(Breakpoint::Location { description, .. }) => description.clone(),
+                Some(Breakpoint::Subroutine { name, .. }) => format!("subroutine {:?}", name),
+                None => continue,
+            };
+            writeln!(output, "{:>3} {}", idx + 1, description)?;
+            any = true;
+        }
+        if !any {
+            writeln!(output, "No breakpoints")?;
+        }
+        Ok(())
+    }
+
+    fn print_location(&self, output: &mut impl Write) -> io::Result<()> {
+        let (source_idx, instruction) = match self.runtime.fetch_instruction() {
+            Ok(instruction) => instruction,
+            Err(_) => return writeln!(output, "At the end of the program"),
+        };
+        let (file, line, col) = self.runtime.get_program().locate(*source_idx);
+        let path = match (file.start, &file.path) {
+            // Only name the file for instructions that come from loaded files.
+            (0, _) | (_, None) => String::new(),
+            (_, Some(path)) => format!("{}:", path),
+        };
+        writeln!(output, "{}{}:{} {}", path, line, col, instruction)
+    }
+}
+
+fn collect_positions(positions: &mut Vec<usize>, instructions: &InstructionSeq) {
+    for (source_idx, instruction) in instructions {
+        positions.push(*source_idx);
+        if let Instruction::Block(block) | Instruction::Fork(block) = instruction {
+            collect_positions(positions, block);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Debugger;
+    use crate::metatape::{parse_str, BitSequence, Runtime, RuntimeError};
+
+    /// Runs the debugger with the given commands, returning its output
+    /// without prompts and the error that ended the program.
+    fn debug(source: &str, commands: &str) -> (String, Option<RuntimeError>) {
+        let runtime = Runtime::new(parse_str(source).unwrap()).with_output(std::io::sink());
+        let mut debugger = Debugger::new(runtime);
+        let mut output = vec![];
+        let commands = commands.lines().map(str::to_owned);
+        let result = debugger.run(commands, &mut output).unwrap();
+        let output = String::from_utf8(output)
+            .unwrap()
+            .replace("(metatape) ", "");
+        (output, result)
+    }
+
+    #[test]
+    fn test_step_and_next() {
+        let source = "e !a\nx\n@ a { < > }";
+        let (output, result) = debug(source, "s\ns\ns\n\nq");
+        assert_eq!(
+            output,
+            "1:1 Enter\n1:3 Call(\"a\")\n3:7 Left\n3:9 Right\n2:1 Exit\n"
+        );
+        assert!(result.is_none());
+        let (output, _) = debug(source, "s\nn\nn");
+        assert_eq!(
+            output,
+            "1:1 Enter\n1:3 Call(\"a\")\n2:1 Exit\nAt the end of the program\n\n"
+        );
+    }
+
+    #[test]
+    fn test_breakpoints() {
+        let source = "e !a\nx !a\n@ a { < > }";
+        let (output, result) = debug(source, "b a\nb 2\nb 3:9\nb\nc\nc\nc\nd 1\nc\nc");
+        assert_eq!(
+            output,
+            "1:1 Enter\n\
+             Breakpoint 1 at a\n\
+             Breakpoint 2 at 2\n\
+             Breakpoint 3 at 3:9\n  \
+             1 subroutine \"a\"\n  \
+             2 2\n  \
+             3 3:9\n\
+             Breakpoint 1\n3:7 Left\n\
+             Breakpoint 3\n3:9 Right\n\
+             Breakpoint 2\n2:1 Exit\n\
+             Deleted breakpoint 1\n\
+             Breakpoint 3\n3:9 Right\n\
+             Program exited because EndOfProgram\n",
+        );
+        assert!(matches!(result, Some(RuntimeError::EndOfProgram)));
+    }
+
+    #[test]
+    fn test_finish() {
+        let (output, _) = debug("!a\n@ a { < { > > } < }", "f\ns\ns\ns\nf\nf");
+        assert_eq!(
+            output,
+            "1:1 Call(\"a\")\n\
+             Not in a subroutine, block, or fork\n\
+             2:7 Left\n2:9 Block(...)\n2:11 Right\n\
+             2:17 Left\n\
+             At the end of the program\n\n",
+        );
+    }
+
+    #[test]
+    fn test_halt() {
+        let (output, _) = debug("e h x", "c\nc\nc");
+        assert_eq!(
+            output,
+            "1:1 Enter\nHalted\n1:3 Halt\nProgram exited because EndOfProgram\n",
+        );
+    }
+
+    #[test]
+    fn test_backtrace() {
+        let (output, _) = debug("e !a\n@ a { < > }", "s\ns\nbt");
+        assert_eq!(
+            output,
+            "1:1 Enter\n1:3 Call(\"a\")\n2:7 Left\n\
+             at 2:7\n\
+             in !{a} called from the main program at 1:3\n\n",
+        );
+    }
+
+    #[test]
+    fn test_reverse() {
+        let (output, _) = debug("ex >\nex < n", "b 2:6\nc\nrs\nrs\nlc\nrc\nrs\nlr");
+        assert_eq!(
+            output,
+            "1:1 Enter\n\
+             Breakpoint 1 at 2:6\n\
+             Breakpoint 1\n2:6 Null\n\
+             2:4 Left\n\
+             2:2 Exit\n\
+             The current cell has not changed since the start of the history\n\
+             1:1 Enter\n\
+             No earlier instructions were recorded\n1:1 Enter\n\
+             No `?` has been executed since the start of the history\n\n",
+        );
+        let (output, _) = debug("ex > ex\n< n >", "b 2:5\nc\nlc\nlc");
+        assert!(output.ends_with("2:3 Null\n1:1 Enter\n\n"), "{}", output);
+    }
+
+    #[test]
+    fn test_replay_random() {
+        let runtime = Runtime::new(parse_str("ex ? ?").unwrap())
+            .with_random_source("01".parse::<BitSequence>().unwrap());
+        let mut debugger = Debugger::new(runtime);
+        let commands = ["b 1:6", "c", "lr", "s", "t"].iter().map(|s| s.to_string());
+        let mut output = vec![];
+        debugger.run(commands, &mut output).unwrap();
+        let output = String::from_utf8(output)
+            .unwrap()
+            .replace("(metatape) ", "");
+        assert!(
+            output.ends_with("1:4 Random\n1:6 Random\n[_]\n\n"),
+            "{}",
+            output
+        );
+    }
+
+    #[test]
+    fn test_tape_depth() {
+        let (output, _) = debug("ex > e > ex < .", "b 1:15\nc\nt\nt 1\nt 0\nt x");
+        assert!(
+            output.ends_with("[_] 0 \n[_] [*_ [_]]\n*_ [...]\nInvalid depth \"x\"\n\n"),
+            "{}",
+            output,
+        );
+    }
+}
@@ -0,0 +1,252 @@
+//! Recorded states of a runtime, for running a program backwards in the
+//! debugger.
+
+use std::collections::VecDeque;
+
+use super::program::{Instruction, SeekDirection};
+use super::runtime::{seek, FrameKind, Snapshot};
+use super::tape::Head;
+
+/// Maximum number of instructions that can be undone.
+const HISTORY_LIMIT: usize = 100_000;
+
+/// State of a runtime before executing an instruction.
+pub struct Entry {
+    pub snapshot: Snapshot,
+    /// Whether the program had stopped at `h`.
+    pub halted: bool,
+}
+
+/// States of a runtime before each of the most recent instructions, oldest
+/// first.
+#[derive(Default)]
+pub struct History(VecDeque<Entry>);
+
+impl History {
+    pub fn push(&mut self, snapshot: Snapshot, halted: bool) {
+        if self.0.len() == HISTORY_LIMIT {
+            self.0.pop_front();
+        }
+        self.0.push_back(Entry { snapshot, halted });
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Removes the state with the given index and every state after it,
+    /// returning the removed state.
+    pub fn rewind(&mut self, idx: usize) -> Option<Entry> {
+        let ret = self.0.drain(idx..).next();
+        ret
+    }
+
+    /// Returns the index of the last state that matches a predicate.
+    pub fn rposition(&self, predicate: impl Fn(&Snapshot) -> bool) -> Option<usize> {
+        self.0.iter().rposition(|entry| predicate(&entry.snapshot))
+    }
+
+    /// Returns the index of the state before the last instruction that
+    /// changed the cell that is under the head in `current`. A cell changes
+    /// when anything inside it changes, including the position that `e` would
+    /// return to.
+    pub fn last_change(&self, current: &Snapshot) -> Option<usize> {
+        match self.search(CellPath::here(), current, 0, self.0.len()) {
+            Search::Changed(idx) => Some(idx),
+            Search::Unchanged | Search::Unknown => None,
+        }
+    }
+
+    /// Searches the instructions after the states with indices from `start`
+    /// to `end` (exclusive) for the last one that changed the cell at `path`
+    /// from the head in `after`, which is the state after those instructions.
+    fn search<'a>(
+        &'a self,
+        mut path: CellPath,
+        mut after: &'a Snapshot,
+        start: usize,
+        end: usize,
+    ) -> Search {
+        let mut idx = end;
+        while idx > start {
+            idx -= 1;
+            let before = &self.0[idx].snapshot;
+            let mut path_before = path.clone();
+            for direction in moves(before).into_iter().rev() {
+                path_before.after_move(direction.opposite());
+            }
+            let changed = !path_before
+                .follow(&before.head)
+                .child_eq(&path.follow(&after.head));
+
+            let popped_fork = before
+                .call_stack
+                .get(after.call_stack.len()..)
+                .and_then(|popped| {
+                    popped
+                        .iter()
+                        .position(|frame| frame.kind == FrameKind::Fork)
+                })
+                .map(|fork_idx| after.call_stack.len() + fork_idx);
+            if let Some(depth) = popped_fork {
+                let fork_start = match self
+                    .0
+                    .range(start..idx)
+                    .rposition(|entry| entry.snapshot.call_stack.len() == depth)
+                {
+                    Some(fork_start) => start + fork_start,
+                    None => return Search::Unknown,
+                };
+                // When a fork returns, everything except the cell where it
+                // started goes back to how it was before the fork.
+                let before_fork = &self.0[fork_start].snapshot;
+                if path
+                    .follow(&before_fork.head)
+                    .child_eq(&path.follow(&after.head))
+                {
+                    idx = fork_start;
+                    after = before_fork;
+                    continue;
+                }
+                if changed {
+                    return Search::Changed(idx);
+                }
+                // Look for the change inside the fork. If there isn't one,
+                // then the fork copied the contents of some other cell.
+                return match self.search(path_before, before, fork_start + 1, idx) {
+                    Search::Unchanged => Search::Changed(idx),
+                    result => result,
+                };
+            }
+
+            if changed {
+                return Search::Changed(idx);
+            }
+            path = path_before;
+            after = before;
+        }
+        Search::Unchanged
+    }
+}
+
+enum Search {
+    /// The cell was changed by the instruction after the state with this
+    /// index.
+    Changed(usize),
+    Unchanged,
+    /// The history doesn't go back far enough to tell.
+    Unknown,
+}
+
+/// Returns the movements of the head caused by the next instruction.
+fn moves(snapshot: &Snapshot) -> Vec<SeekDirection> {
+    match snapshot.instruction() {
+        Some((_, Instruction::Left)) => vec![SeekDirection::Left],
+        Some((_, Instruction::Right)) => vec![SeekDirection::Right],
+        Some((_, Instruction::Enter)) => vec![SeekDirection::Enter],
+        Some((_, Instruction::Exit)) => vec![SeekDirection::Exit],
+        Some((_, Instruction::Seek(path))) => {
+            let mut ret = vec![];
+            seek(&snapshot.head, path, |direction| ret.push(direction));
+            ret
+        }
+        _ => vec![],
+    }
+}
+
+/// Path from the head to a cell: exit `ups` times, move `xs[0]` cells to the
+/// right, and then for each of the rest of `xs`, enter and move that many
+/// cells to the right. Paths never exit and then enter the same cell.
+#[derive(Debug, Clone)]
+struct CellPath {
+    ups: usize,
+    xs: Vec<isize>,
+}
+
+impl CellPath {
+    fn here() -> Self {
+        Self {
+            ups: 0,
+            xs: vec![0],
+        }
+    }
+
+    /// Updates the path to lead to the same cell after the head moves.
+    fn after_move(&mut self, direction: SeekDirection) {
+        match direction {
+            SeekDirection::Left if self.ups == 0 => self.xs[0] += 1,
+            SeekDirection::Right if self.ups == 0 => self.xs[0] -= 1,
+            SeekDirection::Left | SeekDirection::Right => (),
+            SeekDirection::Enter => {
+                if self.ups == 0 && self.xs[0] == 0 && self.xs.len() > 1 {
+                    self.xs.remove(0);
+                } else {
+                    self.ups += 1;
+                }
+            }
+            SeekDirection::Exit => {
+                if self.ups == 0 {
+                    self.xs.insert(0, 0);
+                } else {
+                    self.ups -= 1;
+                }
+            }
+        }
+    }
+
+    /// Moves a head to the cell.
+    fn follow(&self, head: &Head) -> Head {
+        let mut head = head.clone();
+        for _ in 0..self.ups {
+            head = head.exit();
+        }
+        for (idx, &x) in self.xs.iter().enumerate() {
+            if idx > 0 {
+                head = head.enter();
+            }
+            let move_head = if x < 0 {
+                Head::move_left
+            } else {
+                Head::move_right
+            };
+            for _ in 0..x.abs() {
+                head = move_head(&head);
+            }
+        }
+        head
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::History;
+    use crate::metatape::{parse_str, Runtime};
+
+    /// Runs a program, returning the number of instructions before the last
+    /// change to the cell under the head.
+    fn last_change(source: &str) -> Option<usize> {
+        let mut runtime = Runtime::new(parse_str(source).unwrap()).with_output(std::io::sink());
+        let mut history = History::default();
+        while runtime.fetch_instruction().is_ok() {
+            history.push(runtime.snapshot(), false);
+            runtime.step().unwrap();
+        }
+        history.last_change(&runtime.snapshot())
+    }
+
+    #[test]
+    fn test_last_change() {
+        assert_eq!(last_change("ex > ex <"), Some(0));
+        assert_eq!(last_change("ex > ex"), Some(3));
+        assert_eq!(last_change("> < > <"), None);
+        // Changes inside the cell count.
+        assert_eq!(last_change("ex e ex x x"), Some(3));
+        assert_eq!(last_change("ex f{ > n < n } o"), Some(7));
+        // Changes to other cells during a fork are undone.
+        assert_eq!(last_change("ex f{ > ex < n } >"), None);
+        // A fork copies the contents of the cell where it ends.
+        assert_eq!(last_change("f{ ex > ex } f{ > n }"), Some(10));
+        assert_eq!(last_change("ex > ex ^{<} n ^{>}"), Some(3));
+        assert_eq!(last_change("ex > ex > ^{<*} n ^{>2}"), Some(3));
+    }
+}
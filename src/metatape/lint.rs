@@ -0,0 +1,208 @@
+//! Warnings about code that is valid but is probably a mistake.
+
+use std::collections::HashSet;
+
+use super::parser::{ParseError, ParseErrorKind};
+use super::program::{Instruction, InstructionSeq, Program};
+
+impl Program {
+    /// Returns warnings about unused subroutines, unreachable instructions,
+    /// empty loops, and conditions with more than one `|`.
+    ///
+    /// Subroutines are only reported as unused if they are defined in the
+    /// main file and the main file has top-level instructions, because a file
+    /// with no top-level instructions is probably meant to be loaded by other
+    /// programs.
+    pub fn lint(&self) -> Vec<ParseError> {
+        let mut warnings = vec![];
+        self.lint_seq(&mut warnings, &self.instructions);
+        for subroutine in self.subroutines.values() {
+            self.lint_seq(&mut warnings, subroutine);
+        }
+        self.lint_unused_subroutines(&mut warnings);
+        warnings.sort_by_key(|(source_idx, _)| *source_idx);
+        warnings.into_iter().map(|(_, warning)| warning).collect()
+    }
+
+    fn lint_seq(&self, warnings: &mut Vec<(usize, ParseError)>, instructions: &InstructionSeq) {
+        let mut warn = |start: usize, end: usize, kind: ParseErrorKind| {
+            warnings.push((start, ParseError::new(kind, Some(self.span(start, end)))));
+        };
+        let reachable = reachable_instructions(instructions);
+        // Only the first `|` after the `(` is useful.
+        let mut chained = vec![false; instructions.len()];
+        let mut nested_blocks = vec![];
+        for (idx, (source_idx, instruction)) in instructions.iter().enumerate() {
+            if !reachable[idx] {
+                // Report each run of unreachable instructions once.
+                if idx == 0 || reachable[idx - 1] {
+                    let run_len = reachable[idx..].iter().take_while(|r| !**r).count();
+                    let last_source_idx = instructions[idx + run_len - 1].0;
+                    warn(
+                        *source_idx,
+                        last_source_idx + 1,
+                        ParseErrorKind::UnreachableCode,
+                    );
+                }
+                continue;
+            }
+            match instruction {
+                Instruction::Else(destination) => {
+                    if let Instruction::Else(_) = instructions[*destination].1 {
+                        if !chained[idx] {
+                            let else_idx = instructions[*destination].0;
+                            warn(else_idx, else_idx + 1, ParseErrorKind::ChainedElse);
+                        }
+                        chained[*destination] = true;
+                    }
+                }
+                Instruction::EndLoop(destination) => {
+                    let body = &instructions[*destination + 1..idx];
+                    if body.iter().all(|(_, i)| matches!(i, Instruction::Nop)) {
+                        let loop_idx = instructions[*destination].0;
+                        warn(loop_idx, source_idx + 1, ParseErrorKind::EmptyLoop);
+                    }
+                }
+                Instruction::Block(block) | Instruction::Fork(block) => nested_blocks.push(block),
+                _ => (),
+            }
+        }
+        for block in nested_blocks {
+            self.lint_seq(warnings, block);
+        }
+    }
+
+    fn lint_unused_subroutines(&self, warnings: &mut Vec<(usize, ParseError)>) {
+        if self.instructions.is_empty() {
+            return;
+        }
+        let mut called = HashSet::new();
+        let mut queue = vec![&self.instructions];
+        while let Some(instructions) = queue.pop() {
+            let mut names = vec![];
+            collect_calls(&mut names, instructions);
+            for name in names {
+                if called.insert(name) {
+                    queue.extend(self.subroutines.get(name));
+                }
+            }
+        }
+        let main_file_end = self
+            .files
+            .get(1)
+            .map_or(self.source.len(), |file| file.start);
+        for (name, &source_idx) in &self.definitions {
+            if source_idx < main_file_end && !called.contains(name.as_str()) {
+                let kind = ParseErrorKind::UnusedSubroutine(name.clone());
+                let span = self.span(source_idx, source_idx + 1);
+                warnings.push((source_idx, ParseError::new(kind, Some(span))));
+            }
+        }
+    }
+}
+
+/// Returns whether each instruction can be reached from the start of the
+/// sequence, assuming that every condition can go either way and that every
+/// call returns. Execution can continue after `h`, so it doesn't make the
+/// rest of the sequence unreachable. A jump counts as reaching its
+/// destination, even though the destination itself is skipped.
+fn reachable_instructions(instructions: &InstructionSeq) -> Vec<bool> {
+    let mut reachable = vec![false; instructions.len()];
+    let mut visited = vec![false; instructions.len()];
+    let mut stack = vec![0];
+    let mut jump = |stack: &mut Vec<usize>, destination: usize| {
+        reachable[destination] = true;
+        stack.push(destination + 1);
+    };
+    while let Some(idx) = stack.pop() {
+        if idx >= instructions.len() || visited[idx] {
+            continue;
+        }
+        visited[idx] = true;
+        match instructions[idx].1 {
+            Instruction::If(destination) => {
+                stack.push(idx + 1);
+                jump(&mut stack, destination);
+            }
+            Instruction::Else(destination) | Instruction::EndLoop(destination) => {
+                jump(&mut stack, destination);
+            }
+            _ => stack.push(idx + 1),
+        }
+    }
+    reachable
+        .iter()
+        .zip(visited)
+        .map(|(&r, v)| r || v)
+        .collect()
+}
+
+fn collect_calls<'a>(names: &mut Vec<&'a str>, instructions: &'a InstructionSeq) {
+    for (_, instruction) in instructions {
+        match instruction {
+            Instruction::Call(name, _) => names.push(name),
+            Instruction::Block(block) | Instruction::Fork(block) => collect_calls(names, block),
+            _ => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::metatape::parse_str;
+    use crate::metatape::parser::ParseErrorKind::{self, *};
+
+    fn assert_lints(source: &str, expected: &[(ParseErrorKind, (usize, usize))]) {
+        let program = parse_str(source).expect("failed to parse");
+        let actual: Vec<_> = program
+            .lint()
+            .into_iter()
+            .map(|w| (w.kind, w.span.expect("expected a span").start_line_col))
+            .collect();
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn test_unreachable_code() {
+        assert_lints("[o]ex", &[(UnreachableCode, (1, 4))]);
+        assert_lints("[o]\n.o", &[(UnreachableCode, (2, 1))]);
+        // The program can be resumed after halting.
+        assert_lints("h\n.o", &[]);
+        assert_lints("{ eh x } o", &[]);
+        assert_lints("([o(]))x", &[]);
+        assert_lints("[i(|])x", &[]);
+        // Unreachable blocks aren't reported twice.
+        assert_lints("h{h o}", &[]);
+        assert_lints("[o]{[o] o}", &[(UnreachableCode, (1, 4))]);
+    }
+
+    #[test]
+    fn test_empty_loop() {
+        assert_lints("([])", &[(EmptyLoop, (1, 2))]);
+        assert_lints("([ . ])", &[(EmptyLoop, (1, 2))]);
+        assert_lints("f{ [] }", &[(EmptyLoop, (1, 4))]);
+        assert_lints("[(])", &[]);
+    }
+
+    #[test]
+    fn test_chained_else() {
+        assert_lints("(o|i|x)", &[(ChainedElse, (1, 5))]);
+        assert_lints("(o|i|x|e)", &[(ChainedElse, (1, 5))]);
+        assert_lints("(o|i)(|x)", &[]);
+    }
+
+    #[test]
+    fn test_unused_subroutines() {
+        let unused = |name: &str| UnusedSubroutine(name.to_owned());
+        assert_lints(
+            "@ a { !b }\n@ b { }\n@ c { !d }\n@ d { !c }\n@ e { }\n!a",
+            &[
+                (unused("c"), (3, 1)),
+                (unused("d"), (4, 1)),
+                (unused("e"), (5, 1)),
+            ],
+        );
+        // Files with only subroutines are libraries.
+        assert_lints("@ a { }", &[]);
+    }
+}
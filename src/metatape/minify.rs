@@ -0,0 +1,279 @@
+//! Conversion of programs to the shortest equivalent ASCII source code.
+
+use std::collections::{HashMap, HashSet};
+
+use super::program::{Instruction, InstructionSeq, Program};
+
+/// Options that control how programs are minified.
+#[derive(Debug, Default, Clone)]
+pub struct MinifyOptions {
+    /// Whether to replace each call to a subroutine that is called exactly
+    /// once (and is not recursive) with the body of the subroutine.
+    pub inline: bool,
+    /// Whether to rename subroutines to single characters where possible,
+    /// giving the shortest names to the most-called subroutines.
+    pub rename: bool,
+}
+
+struct Minifier<'a> {
+    program: &'a Program,
+    /// Subroutines whose calls are replaced by their bodies.
+    inlined: HashSet<&'a str>,
+    /// New name of each renamed subroutine.
+    names: HashMap<&'a str, String>,
+}
+
+/// Returns ASCII source code for a program with no comments, whitespace, or
+/// blocks (except where required), and with loaded files included.
+pub fn minify(program: &Program, options: &MinifyOptions) -> String {
+    let mut call_counts: HashMap<&str, usize> = HashMap::new();
+    count_calls(&mut call_counts, &program.instructions);
+    for subroutine in program.subroutines.values() {
+        count_calls(&mut call_counts, subroutine);
+    }
+
+    let mut minifier = Minifier {
+        program,
+        inlined: HashSet::new(),
+        names: HashMap::new(),
+    };
+    if options.inline {
+        minifier.inlined = program
+            .subroutines
+            .keys()
+            .map(String::as_str)
+            .filter(|name| call_counts.get(name) == Some(&1) && !minifier.is_recursive(name))
+            .collect();
+    }
+
+    let mut defined: Vec<&str> = program
+        .subroutines
+        .keys()
+        .map(String::as_str)
+        .filter(|name| !minifier.inlined.contains(name))
+        .collect();
+    // Most-called first, so that they get the shortest names.
+    defined.sort_by_key(|name| (std::cmp::Reverse(call_counts.get(name)), *name));
+    if options.rename {
+        // Calls to undefined subroutines keep their names, so don't reuse
+        // those names.
+        let reserved: HashSet<&str> = call_counts
+            .keys()
+            .filter(|name| !program.subroutines.contains_key(**name))
+            .copied()
+            .collect();
+        let mut new_names = short_names().filter(|name| !reserved.contains(name.as_str()));
+        for name in &defined {
+            let new_name = new_names.next().expect("Ran out of subroutine names");
+            minifier.names.insert(name, new_name);
+        }
+    }
+
+    let mut ret = String::new();
+    minifier.write_seq(&mut ret, &program.instructions);
+    defined.sort_by_key(|name| minifier.name(name));
+    for name in defined {
+        ret.push('@');
+        ret.push_str(&minifier.name(name));
+        ret.push('{');
+        minifier.write_seq(&mut ret, &program.subroutines[name]);
+        ret.push('}');
+    }
+    ret
+}
+
+impl<'a> Minifier<'a> {
+    fn name(&self, name: &'a str) -> String {
+        self.names
+            .get(name)
+            .cloned()
+            .unwrap_or_else(|| name.to_owned())
+    }
+
+    /// Returns whether a subroutine can call itself, directly or indirectly.
+    fn is_recursive(&self, name: &str) -> bool {
+        let mut visited = HashSet::new();
+        let mut queue = vec![name];
+        while let Some(caller) = queue.pop() {
+            let mut callees = HashMap::new();
+            if let Some(body) = self.program.subroutines.get(caller) {
+                count_calls(&mut callees, body);
+            }
+            for callee in callees.into_keys() {
+                if callee == name {
+                    return true;
+                }
+                if visited.insert(callee) {
+                    queue.push(callee);
+                }
+            }
+        }
+        false
+    }
+
+    /// Writes a sequence of instructions, returning the number of
+    /// instructions written.
+    fn write_seq(&self, ret: &mut String, instructions: &InstructionSeq) -> usize {
+        instructions
+            .iter()
+            .map(|(_, instruction)| self.write_instruction(ret, instruction))
+            .sum()
+    }
+
+    /// Writes an instruction, returning the number of instructions written
+    /// (which is not 1 for blocks, inlined calls, and no-ops).
+    fn write_instruction(&self, ret: &mut String, instruction: &Instruction) -> usize {
+        match instruction {
+            Instruction::Nop => return 0,
+            Instruction::Left => ret.push('<'),
+            Instruction::Right => ret.push('>'),
+            Instruction::Enter => ret.push('e'),
+            Instruction::Exit => ret.push('x'),
+            Instruction::Null => ret.push('n'),
+            Instruction::If(_) => ret.push('('),
+            Instruction::Else(_) => ret.push('|'),
+            Instruction::EndIf => ret.push(')'),
+            Instruction::Loop => ret.push('['),
+            Instruction::EndLoop(_) => ret.push(']'),
+            // Conditions and loops can't cross the edge of a block, so a
+            // block behaves the same as its contents.
+            Instruction::Block(block) => return self.write_seq(ret, block),
+            Instruction::Random => ret.push('?'),
+            Instruction::Input => ret.push('i'),
+            Instruction::Output => ret.push('o'),
+            Instruction::Halt => ret.push('h'),
+            Instruction::IOMode(mode) => write_string_instruction(ret, '%', &mode.to_string()),
+            Instruction::Seek(path) => {
+                let path: String = path.to_string().split_whitespace().collect();
+                write_string_instruction(ret, '^', &path);
+            }
+            Instruction::Call(name, _) if self.inlined.contains(name.as_str()) => {
+                return self.write_seq(ret, &self.program.subroutines[name]);
+            }
+            Instruction::Call(name, _) => write_string_instruction(ret, '!', &self.name(name)),
+            Instruction::Fork(block) => {
+                let mut body = String::new();
+                ret.push('f');
+                if self.write_seq(&mut body, block) == 1 {
+                    ret.push_str(&body);
+                } else {
+                    ret.push('{');
+                    ret.push_str(&body);
+                    ret.push('}');
+                }
+            }
+        }
+        1
+    }
+}
+
+fn count_calls<'a>(counts: &mut HashMap<&'a str, usize>, instructions: &'a InstructionSeq) {
+    for (_, instruction) in instructions {
+        match instruction {
+            Instruction::Call(name, _) => *counts.entry(name).or_insert(0) += 1,
+            Instruction::Block(block) | Instruction::Fork(block) => count_calls(counts, block),
+            _ => (),
+        }
+    }
+}
+
+fn write_string_instruction(ret: &mut String, prefix: char, arg: &str) {
+    ret.push(prefix);
+    if arg.chars().count() == 1 {
+        ret.push_str(arg);
+    } else {
+        ret.push('{');
+        ret.push_str(arg);
+        ret.push('}');
+    }
+}
+
+/// Returns every possible subroutine name, shortest first.
+fn short_names() -> impl Iterator<Item = String> {
+    let chars: Vec<char> = ('a'..='z')
+        .chain('A'..='Z')
+        .chain('0'..='9')
+        .chain("!\"$%&'*+,-.:;<=>?[]^_`|~()#@\\".chars())
+        .collect();
+    (1..).flat_map(move |len| {
+        let chars = chars.clone();
+        (0..chars.len().pow(len)).map(move |mut idx| {
+            let mut name = String::new();
+            for _ in 0..len {
+                name.push(chars[idx % chars.len()]);
+                idx /= chars.len();
+            }
+            name
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{minify, MinifyOptions};
+    use crate::metatape::parse_str;
+
+    fn assert_minified(source: &str, inline: bool, rename: bool, expected: &str) {
+        let program = parse_str(source).unwrap();
+        let minified = minify(&program, &MinifyOptions { inline, rename });
+        assert_eq!(minified, expected);
+        assert!(
+            parse_str(&minified).is_ok(),
+            "{:?} does not parse",
+            minified
+        );
+    }
+
+    #[test]
+    fn test_minify() {
+        assert_minified("  e . x // comment\n{ o /* */ i }", false, false, "exoi");
+        assert_minified(
+            "f{ e }f{ex}f!{a b}f!a@ a{} @ a b {}",
+            false,
+            false,
+            "fef{ex}f!{a b}f!a@a{}@a b{}",
+        );
+        assert_minified("%{ lsb }^{ x2 <* }^<", false, false, "%{lsb}^{x2<*}^<");
+    }
+
+    #[test]
+    fn test_inline() {
+        let source = "!{a b} !c !c @ a b { e !d } @ c { o } @ d { (x|!d) }";
+        assert_minified(source, true, false, "e!d!c!c@c{o}@d{(x|!d)}");
+        assert_minified(source, true, true, "e!b!a!a@a{o}@b{(x|!b)}");
+    }
+
+    #[test]
+    fn test_rename() {
+        assert_minified(
+            "!{x y} !z !z @ x y { !{x y} } @ z { }",
+            false,
+            true,
+            "!a!b!b@a{!a}@b{}",
+        );
+        // Undefined names are not reused.
+        let options = crate::metatape::ParseOptions {
+            allow_undefined_calls: true,
+            ..Default::default()
+        };
+        let program = crate::metatape::parser::parse(b"!a !q @ q { }".to_vec(), &options, None);
+        let minified = minify(
+            &program.unwrap(),
+            &MinifyOptions {
+                inline: false,
+                rename: true,
+            },
+        );
+        assert_eq!(minified, "!a!b@b{}");
+    }
+
+    #[test]
+    fn test_readme_example() {
+        let source = std::fs::read_to_string("examples/cat_null.mt").unwrap();
+        let minified = minify(&parse_str(&source).unwrap(), &MinifyOptions::default());
+        assert_eq!(
+            minified,
+            "[ex>eex<<<<<<<<[eexix>(n|])[<(])[>(eo(xx<n>e|x)])xn<(|])"
+        );
+    }
+}
@@ -1,19 +1,88 @@
 mod debug;
+mod debugger;
+mod history;
+mod lint;
+mod minify;
 mod parser;
 mod program;
 mod runtime;
 mod tape;
 
+pub use runtime::RandomSource;
+
+pub type BitSequence = runtime::BitSequence;
+pub type Debugger = debugger::Debugger;
+pub type EofPolicy = runtime::EofPolicy;
+pub type ExecDebugInfo = runtime::ExecDebugInfo;
+pub type Frame = runtime::Frame;
+pub type FrameKind = runtime::FrameKind;
+pub type Head = tape::Head;
+pub type IOMode = runtime::IOMode;
+pub type MinifyOptions = minify::MinifyOptions;
+pub type Instruction = program::Instruction;
+pub type InstructionBlock = program::InstructionBlock;
+pub type ParseError = parser::ParseError;
+pub type ParseErrorKind = parser::ParseErrorKind;
+pub type ParseOptions = parser::ParseOptions;
+pub type PartialOutputPolicy = runtime::PartialOutputPolicy;
 pub type Program = program::Program;
+pub type RenderOptions = tape::RenderOptions;
 pub type Runtime = runtime::Runtime;
 pub type RuntimeError = runtime::RuntimeError;
+pub type SeekPath = program::SeekPath;
+pub type SharedBuffer = runtime::SharedBuffer;
+pub type Snapshot = runtime::Snapshot;
+pub type SourceFile = program::SourceFile;
+pub type SourceFormat = parser::SourceFormat;
+pub type Span = parser::Span;
+
+/// Parses a program from ASCII source code, using the default options. Files
+/// loaded by the program are found relative to the current directory.
+pub fn parse_str(source: &str) -> Result<Program, Vec<ParseError>> {
+    parse_str_with_options(source, &ParseOptions::default())
+}
+
+/// Parses a program from ASCII source code, ignoring `options.format`. Files
+/// loaded by the program are found relative to the current directory.
+pub fn parse_str_with_options(
+    source: &str,
+    options: &ParseOptions,
+) -> Result<Program, Vec<ParseError>> {
+    let options = ParseOptions {
+        format: Some(SourceFormat::Ascii),
+        ..options.clone()
+    };
+    parser::parse(source.as_bytes().to_vec(), &options, None)
+}
+
+/// Parses a program from a file, returning all of the errors found if there
+/// are any.
+pub fn program_from_file(
+    filename: &str,
+    options: &ParseOptions,
+) -> Result<Program, Vec<ParseError>> {
+    let file_contents = std::fs::read(filename).map_err(|err| {
+        let kind = ParseErrorKind::LoadFailed {
+            path: filename.to_owned(),
+            reason: err.to_string(),
+        };
+        vec![ParseError::new(kind, None)]
+    })?;
+    parser::parse(file_contents, options, Some(std::path::Path::new(filename)))
+}
+
+/// Formats ASCII source code, as described under "Formatting" in the README:
+/// comments and line breaks are kept, other whitespace is collapsed, and
+/// lines are indented by four spaces for each unclosed `{`, `(`, or `[`.
+pub fn format_source(source: &str) -> Result<String, ParseError> {
+    parser::format::format(source)
+}
 
-pub fn program_from_file(filename: &str) -> Result<Program, String> {
-    Ok(filename)
-        .and_then(|filename| std::fs::read_to_string(filename).map_err(|err| err.to_string()))
-        .and_then(|file_contents| parser::parse(file_contents).map_err(|err| err.to_string()))
+/// Returns the shortest ASCII source code for a program. See `MinifyOptions`.
+pub fn minify(program: &Program, options: &MinifyOptions) -> String {
+    minify::minify(program, options)
 }
 
-pub fn runtime_from_file(filename: &str) -> Result<Runtime, String> {
-    program_from_file(filename).map(|program| Runtime::new(program))
+pub fn golf_encode(program: &Program) -> Vec<u8> {
+    parser::golf::encode(program)
 }
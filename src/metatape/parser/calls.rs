@@ -0,0 +1,164 @@
+//! Checks that every subroutine that is called is defined, and assigns call
+//! IDs so that calls can be linked to subroutines.
+
+use pest::Parser;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use super::{Grammar, Loader, ParseError, ParseErrorKind, Rule, Span};
+use crate::metatape::program::{Instruction, InstructionSeq};
+
+impl Loader {
+    /// Records an error for each call to an undefined subroutine, either in
+    /// the given top-level instructions or in any subroutine.
+    pub(super) fn check_calls(&mut self, instructions: &InstructionSeq) {
+        let mut calls = vec![];
+        collect_calls(&mut calls, instructions);
+        for subroutine in self.subroutines.values() {
+            collect_calls(&mut calls, subroutine);
+        }
+        calls.sort();
+        for (source_idx, name) in calls {
+            if self.subroutines.contains_key(name) {
+                continue;
+            }
+            let kind = ParseErrorKind::UndefinedSubroutine {
+                name: name.to_owned(),
+                suggestion: suggest_name(name, self.subroutines.keys()),
+            };
+            let err = ParseError::new(kind, Some(self.call_span(source_idx)));
+            self.errors.push(err);
+        }
+    }
+
+    /// Assigns a call ID to every call, either in the given top-level
+    /// instructions or in any subroutine, and returns the name for each ID.
+    pub(super) fn assign_call_ids(&mut self, instructions: &mut InstructionSeq) -> Vec<String> {
+        let mut ids = HashMap::new();
+        assign_call_ids(&mut ids, instructions);
+        // Sort subroutines so that IDs don't depend on hash order.
+        let mut subroutines: Vec<_> = self.subroutines.iter_mut().collect();
+        subroutines.sort_by_key(|(name, _)| *name);
+        for (_, subroutine) in subroutines {
+            let subroutine = Rc::get_mut(subroutine).expect("Subroutine is shared");
+            assign_call_ids(&mut ids, subroutine);
+        }
+        let mut names = vec![String::new(); ids.len()];
+        for (name, id) in ids {
+            names[id] = name;
+        }
+        names
+    }
+
+    /// Returns the span of the call instruction at the given position in the
+    /// program's combined source.
+    fn call_span(&self, source_idx: usize) -> Span {
+        let file_idx = self
+            .files
+            .iter()
+            .rposition(|file| file.start <= source_idx)
+            .expect("No file contains call");
+        let file = &self.files[file_idx];
+        let end = self
+            .files
+            .get(file_idx + 1)
+            .map_or(self.source.len(), |next_file| next_file.start);
+        let file_source = &self.source[file.start..end];
+        let start = source_idx - file.start;
+        let len = Grammar::parse(Rule::string_instruction, &file_source[start..])
+            .ok()
+            .and_then(|mut pairs| pairs.next())
+            .map_or(1, |pair| pair.as_span().end());
+        let mut span = Span::from_pest(pest::Span::new(file_source, start, start + len).unwrap());
+        span.path = file.path.clone();
+        span
+    }
+}
+
+fn collect_calls<'a>(calls: &mut Vec<(usize, &'a str)>, instructions: &'a InstructionSeq) {
+    for (source_idx, instruction) in instructions {
+        match instruction {
+            Instruction::Call(name, _) => calls.push((*source_idx, name)),
+            Instruction::Block(block) | Instruction::Fork(block) => collect_calls(calls, block),
+            _ => (),
+        }
+    }
+}
+
+fn assign_call_ids(ids: &mut HashMap<String, usize>, instructions: &mut InstructionSeq) {
+    for (_, instruction) in instructions {
+        match instruction {
+            Instruction::Call(name, call_id) => {
+                let next_id = ids.len();
+                *call_id = *ids.entry(name.clone()).or_insert(next_id);
+            }
+            Instruction::Block(block) | Instruction::Fork(block) => {
+                assign_call_ids(ids, Rc::get_mut(block).expect("Block is shared"));
+            }
+            _ => (),
+        }
+    }
+}
+
+/// Returns the candidate most similar to `name`, if any is similar enough to
+/// be a likely typo.
+fn suggest_name<'a>(name: &str, candidates: impl Iterator<Item = &'a String>) -> Option<String> {
+    let name_len = name.chars().count();
+    let max_distance = (name_len / 3).max(1);
+    candidates
+        .map(|candidate| {
+            let distance = if candidate.eq_ignore_ascii_case(name) {
+                0
+            } else {
+                edit_distance(name, candidate)
+            };
+            (distance, candidate)
+        })
+        // Replacing every character isn't a typo.
+        .filter(|(distance, candidate)| {
+            *distance <= max_distance && *distance < name_len.max(candidate.chars().count())
+        })
+        .min()
+        .map(|(_, candidate)| candidate.clone())
+}
+
+/// Returns the Levenshtein distance between two strings, in characters.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, a_char) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, &b_char) in b.iter().enumerate() {
+            let substitution = diagonal + (a_char != b_char) as usize;
+            diagonal = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(diagonal + 1);
+        }
+    }
+    row[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{edit_distance, suggest_name};
+
+    #[test]
+    fn test_edit_distance() {
+        assert_eq!(edit_distance("", ""), 0);
+        assert_eq!(edit_distance("abc", ""), 3);
+        assert_eq!(edit_distance("", "abc"), 3);
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+        assert_eq!(edit_distance("new 0", "new 1"), 1);
+        assert_eq!(edit_distance("dec", "dce"), 2);
+    }
+
+    #[test]
+    fn test_suggest_name() {
+        let names = ["abc".to_owned(), "a".to_owned(), "é".to_owned()];
+        assert_eq!(suggest_name("abd", names.iter()).as_deref(), Some("abc"));
+        assert_eq!(suggest_name("A", names.iter()).as_deref(), Some("a"));
+        // Lengths are counted in characters, not bytes.
+        assert_eq!(suggest_name("b", names.iter()), None);
+        assert_eq!(suggest_name("ü", names.iter()), None);
+    }
+}
@@ -0,0 +1,317 @@
+//! Errors and warnings produced while parsing a program.
+
+use std::fmt;
+
+use super::Rule;
+
+/// Location of an error in a source file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Span {
+    /// Path of the file, or `None` if the program was not read from a file.
+    pub path: Option<String>,
+    /// Byte offset of the start of the span within the file.
+    pub start: usize,
+    /// Byte offset of the end of the span within the file.
+    pub end: usize,
+    /// Line and column (in characters) of the start of the span, starting at 1.
+    pub start_line_col: (usize, usize),
+    /// Line and column (in characters) of the end of the span, starting at 1.
+    pub end_line_col: (usize, usize),
+    /// Text of the line containing the start of the span, without the line
+    /// ending.
+    pub line: String,
+}
+
+impl Span {
+    pub(crate) fn from_pest(span: pest::Span<'_>) -> Self {
+        Self {
+            path: None,
+            start: span.start(),
+            end: span.end(),
+            start_line_col: span.start_pos().line_col(),
+            end_line_col: span.end_pos().line_col(),
+            line: span
+                .start_pos()
+                .line_of()
+                .trim_end_matches(&['\r', '\n'][..])
+                .to_owned(),
+        }
+    }
+}
+
+/// Kind of parse error, along with any details that are not part of the span.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseErrorKind {
+    /// The source code does not match the grammar.
+    Syntax(String),
+    InvalidUtf8,
+    /// Golf-encoded escape sequence with an unknown second nibble.
+    UnknownEscape,
+    /// Golf-encoded escape sequence at the end of the file.
+    IncompleteEscape,
+    /// Golf-encoded string at the end of the file with no terminator.
+    IncompleteString,
+    /// Golf-encoded single-character subroutine name that is not allowed in
+    /// ASCII source code.
+    InvalidNameChar,
+    /// `(` with no matching `)`.
+    UnmatchedIf,
+    /// `|` with no matching `(`.
+    UnmatchedElse,
+    /// `)` with no matching `(`.
+    UnmatchedEndIf,
+    /// `[` with no matching `]`.
+    UnmatchedLoop,
+    /// `]` with no matching `[`.
+    UnmatchedEndLoop,
+    DuplicateSubroutine(String),
+    /// Call to a subroutine that is not defined, along with the name of a
+    /// similar subroutine that is defined, if there is one.
+    UndefinedSubroutine {
+        name: String,
+        suggestion: Option<String>,
+    },
+    SubroutineInBlock,
+    LoadInBlock,
+    UnknownInstruction(String),
+    /// Invalid argument to `%`, with a description of the problem.
+    InvalidIOMode(String),
+    /// Invalid argument to `^`, with a description of the problem.
+    InvalidSeekPath(String),
+    LoadFailed {
+        path: String,
+        reason: String,
+    },
+    CyclicLoad(String),
+    /// Warning for a subroutine that is never called, even indirectly, by the
+    /// main file.
+    UnusedSubroutine(String),
+    /// Warning for instructions that can never run.
+    UnreachableCode,
+    /// Warning for a loop that contains no instructions and so can never end.
+    EmptyLoop,
+    /// Warning for a second `|` in the same condition.
+    ChainedElse,
+    /// Warning for a letter instruction whose case does not match the first
+    /// letter instruction in the file.
+    MixedCase {
+        instruction: char,
+        first: char,
+    },
+}
+
+impl ParseErrorKind {
+    /// Returns the name of the kind of error, which is the same for all errors
+    /// of that kind regardless of details.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Syntax(_) => "Syntax",
+            Self::InvalidUtf8 => "InvalidUtf8",
+            Self::UnknownEscape => "UnknownEscape",
+            Self::IncompleteEscape => "IncompleteEscape",
+            Self::IncompleteString => "IncompleteString",
+            Self::InvalidNameChar => "InvalidNameChar",
+            Self::UnmatchedIf => "UnmatchedIf",
+            Self::UnmatchedElse => "UnmatchedElse",
+            Self::UnmatchedEndIf => "UnmatchedEndIf",
+            Self::UnmatchedLoop => "UnmatchedLoop",
+            Self::UnmatchedEndLoop => "UnmatchedEndLoop",
+            Self::DuplicateSubroutine(_) => "DuplicateSubroutine",
+            Self::UndefinedSubroutine { .. } => "UndefinedSubroutine",
+            Self::SubroutineInBlock => "SubroutineInBlock",
+            Self::LoadInBlock => "LoadInBlock",
+            Self::UnknownInstruction(_) => "UnknownInstruction",
+            Self::InvalidIOMode(_) => "InvalidIOMode",
+            Self::InvalidSeekPath(_) => "InvalidSeekPath",
+            Self::LoadFailed { .. } => "LoadFailed",
+            Self::CyclicLoad(_) => "CyclicLoad",
+            Self::UnusedSubroutine(_) => "UnusedSubroutine",
+            Self::UnreachableCode => "UnreachableCode",
+            Self::EmptyLoop => "EmptyLoop",
+            Self::ChainedElse => "ChainedElse",
+            Self::MixedCase { .. } => "MixedCase",
+        }
+    }
+}
+
+impl fmt::Display for ParseErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Syntax(message) => write!(f, "{}", message),
+            Self::InvalidUtf8 => write!(f, "Invalid UTF-8"),
+            Self::UnknownEscape => write!(f, "Unknown escape sequence"),
+            Self::IncompleteEscape => write!(f, "Incomplete escape sequence"),
+            Self::IncompleteString => write!(f, "Incomplete string"),
+            Self::InvalidNameChar => write!(f, "Invalid single-character subroutine name"),
+            Self::UnmatchedIf => write!(f, "No matching 'endif' instruction"),
+            Self::UnmatchedElse | Self::UnmatchedEndIf => write!(f, "No matching 'if' instruction"),
+            Self::UnmatchedLoop => write!(f, "No matching 'endloop' instruction"),
+            Self::UnmatchedEndLoop => write!(f, "No matching 'loop' instruction"),
+            Self::DuplicateSubroutine(name) => {
+                write!(f, "Duplicate subroutine definition with name {:?}", name)
+            }
+            Self::UndefinedSubroutine { name, suggestion } => {
+                write!(f, "Undefined subroutine {:?}", name)?;
+                if let Some(suggestion) = suggestion {
+                    write!(f, " (did you mean {:?}?)", suggestion)?;
+                }
+                Ok(())
+            }
+            Self::SubroutineInBlock => {
+                write!(
+                    f,
+                    "Subroutines cannot be defined inside a subroutine or block"
+                )
+            }
+            Self::LoadInBlock => {
+                write!(
+                    f,
+                    "Files can only be loaded outside of subroutines and blocks"
+                )
+            }
+            Self::UnknownInstruction(s) => write!(f, "Unrecognized instruction: {:?}", s),
+            Self::InvalidIOMode(message) | Self::InvalidSeekPath(message) => {
+                write!(f, "{}", message)
+            }
+            Self::LoadFailed { path, reason } => write!(f, "Unable to load {:?}: {}", path, reason),
+            Self::CyclicLoad(path) => write!(f, "Cyclic load of {:?}", path),
+            Self::UnusedSubroutine(name) => write!(f, "Subroutine {:?} is never called", name),
+            Self::UnreachableCode => write!(f, "Unreachable code"),
+            Self::EmptyLoop => write!(f, "Empty loop never ends"),
+            Self::ChainedElse => write!(
+                f,
+                "Condition has more than one 'else' instruction ('(A|B|C)' is the same as '(AC|B)')"
+            ),
+            Self::MixedCase { instruction, first } => write!(
+                f,
+                "Instruction {:?} does not match the case of the first instruction {:?}",
+                instruction, first,
+            ),
+        }
+    }
+}
+
+/// Error or warning produced while parsing a program.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    pub kind: ParseErrorKind,
+    /// Location of the error, or `None` if it does not refer to any source
+    /// code (e.g. the program file could not be read).
+    pub span: Option<Span>,
+}
+
+impl ParseError {
+    pub fn new(kind: ParseErrorKind, span: Option<Span>) -> Self {
+        Self { kind, span }
+    }
+
+    pub(super) fn from_pest(err: pest::error::Error<Rule>) -> Self {
+        let (start, end) = match err.location {
+            pest::error::InputLocation::Pos(pos) => (pos, pos),
+            pest::error::InputLocation::Span(span) => span,
+        };
+        let (start_line_col, end_line_col) = match err.line_col {
+            pest::error::LineColLocation::Pos(pos) => (pos, pos),
+            pest::error::LineColLocation::Span(start, end) => (start, end),
+        };
+        Self::new(
+            ParseErrorKind::Syntax(err.variant.message().into_owned()),
+            Some(Span {
+                path: None,
+                start,
+                end,
+                start_line_col,
+                end_line_col,
+                line: err.line().trim_end_matches(&['\r', '\n'][..]).to_owned(),
+            }),
+        )
+    }
+
+    /// Returns the path of the file containing the error, if any.
+    pub fn path(&self) -> Option<&str> {
+        self.span.as_ref()?.path.as_deref()
+    }
+
+    /// Sets the path of the file containing the error.
+    pub fn with_path(mut self, path: &str) -> Self {
+        if let Some(span) = &mut self.span {
+            span.path = Some(path.to_owned());
+        }
+        self
+    }
+
+    /// Returns a single-line JSON object describing the error, for tools such
+    /// as editors. `severity` is usually `"error"` or `"warning"`.
+    pub fn to_json(&self, severity: &str) -> String {
+        let mut fields = vec![
+            format!("\"severity\":{}", json_string(severity)),
+            format!("\"kind\":{}", json_string(self.kind.name())),
+            format!("\"message\":{}", json_string(&self.kind.to_string())),
+        ];
+        if let Some(span) = &self.span {
+            fields.push(format!(
+                "\"path\":{}",
+                span.path.as_deref().map_or("null".to_owned(), json_string),
+            ));
+            fields.push(format!(
+                "\"start\":{{\"offset\":{},\"line\":{},\"col\":{}}}",
+                span.start, span.start_line_col.0, span.start_line_col.1,
+            ));
+            fields.push(format!(
+                "\"end\":{{\"offset\":{},\"line\":{},\"col\":{}}}",
+                span.end, span.end_line_col.0, span.end_line_col.1,
+            ));
+        }
+        format!("{{{}}}", fields.join(","))
+    }
+}
+
+/// Formats errors the same way as pest.
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let span = match &self.span {
+            Some(span) => span,
+            None => return write!(f, "{}", self.kind),
+        };
+        let (line, col) = span.start_line_col;
+        let gutter = " ".repeat(line.to_string().len());
+        let path = span
+            .path
+            .as_ref()
+            .map_or(String::new(), |path| format!("{}:", path));
+        let len = if span.end_line_col.0 == line {
+            span.end_line_col.1.saturating_sub(col)
+        } else {
+            0
+        };
+        let underline = match len {
+            0 | 1 => "^".to_owned(),
+            _ => format!("^{}^", "-".repeat(len - 2)),
+        };
+        writeln!(f, "{}--> {}{}:{}", gutter, path, line, col)?;
+        writeln!(f, "{} |", gutter)?;
+        writeln!(f, "{} | {}", line, span.line)?;
+        writeln!(f, "{} | {}{}", gutter, " ".repeat(col - 1), underline)?;
+        writeln!(f, "{} |", gutter)?;
+        write!(f, "{} = {}", gutter, self.kind)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+fn json_string(s: &str) -> String {
+    let mut ret = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => ret.push_str("\\\""),
+            '\\' => ret.push_str("\\\\"),
+            '\n' => ret.push_str("\\n"),
+            '\r' => ret.push_str("\\r"),
+            '\t' => ret.push_str("\\t"),
+            c if c.is_control() => ret.push_str(&format!("\\u{:04x}", c as u32)),
+            c => ret.push(c),
+        }
+    }
+    ret.push('"');
+    ret
+}
@@ -0,0 +1,317 @@
+//! Canonical formatting of ASCII source code.
+//!
+//! The formatter keeps comments and line breaks (collapsing runs of blank
+//! lines), collapses other whitespace to a single space, normalizes subroutine
+//! headers and string arguments the same way the parser reads them, and
+//! indents each line by its depth inside blocks, loops, and conditions.
+
+use pest::Parser;
+
+use super::{Grammar, ParseError, Rule, TokenPair};
+
+const INDENT: &str = "    ";
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum TokenKind {
+    /// `{`, `(`, or `[`.
+    Open,
+    /// `}`, `)`, or `]`.
+    Close,
+    /// `|`.
+    Else,
+    Other,
+}
+
+#[derive(Debug)]
+enum Piece {
+    Token(TokenKind, String),
+    Comment(String),
+    Space,
+    Newline,
+}
+
+/// Token along with its position in the source code.
+struct Token {
+    start: usize,
+    end: usize,
+    kind: TokenKind,
+    text: String,
+    /// Whether the token must be separated from the previous one by a space
+    /// (if they are on the same line).
+    space_before: bool,
+}
+
+/// Formats ASCII source code.
+pub fn format(source: &str) -> Result<String, ParseError> {
+    let main_pair = Grammar::parse(Rule::main, source)
+        .map_err(ParseError::from_pest)?
+        .next()
+        .expect("No main token");
+    let mut tokens = vec![];
+    for pair in main_pair.into_inner() {
+        collect_tokens(&mut tokens, pair);
+    }
+
+    let mut pieces = vec![];
+    let mut prev_end = 0;
+    for token in tokens {
+        let gap = &source[prev_end..token.start];
+        if token.space_before && !gap.contains(&['/', '\n', '\r'][..]) {
+            pieces.push(Piece::Space);
+        } else {
+            gap_pieces(&mut pieces, gap);
+        }
+        pieces.push(Piece::Token(token.kind, token.text));
+        prev_end = token.end;
+    }
+    gap_pieces(&mut pieces, &source[prev_end..]);
+    Ok(render(&pieces))
+}
+
+fn collect_tokens(tokens: &mut Vec<Token>, pair: TokenPair) {
+    let span = pair.as_span();
+    let mut push = |start: usize, end: usize, kind: TokenKind, text: String| {
+        tokens.push(Token {
+            start,
+            end,
+            kind,
+            text,
+            space_before: false,
+        })
+    };
+    match pair.as_rule() {
+        Rule::EOI => (),
+        Rule::instruction => {
+            let inner = pair.into_inner().next().expect("Empty instruction");
+            collect_tokens(tokens, inner);
+        }
+        Rule::subroutine_def => {
+            let mut parts = pair.into_inner();
+            let name = parts
+                .next()
+                .expect("Subroutine definition contains no name");
+            let header = match collapse_words(name.as_str()) {
+                name if name.is_empty() => "@".to_owned(),
+                name => format!("@ {}", name),
+            };
+            push(span.start(), name.as_span().end(), TokenKind::Other, header);
+            let body = parts
+                .next()
+                .expect("Subroutine definition contains no body");
+            let body_start = tokens.len();
+            collect_tokens(tokens, body);
+            tokens[body_start].space_before = true;
+        }
+        Rule::load => {
+            let path = pair.into_inner().next().expect("Load contains no path");
+            let text = format!("#{{{}}}", path.as_str().trim());
+            push(span.start(), span.end(), TokenKind::Other, text);
+        }
+        Rule::block => {
+            push(
+                span.start(),
+                span.start() + 1,
+                TokenKind::Open,
+                "{".to_owned(),
+            );
+            for inner in pair.into_inner() {
+                collect_tokens(tokens, inner);
+            }
+            tokens.push(Token {
+                start: span.end() - 1,
+                end: span.end(),
+                kind: TokenKind::Close,
+                text: "}".to_owned(),
+                space_before: false,
+            });
+        }
+        Rule::block_instruction => {
+            let text = span.as_str()[..1].to_owned();
+            push(span.start(), span.start() + 1, TokenKind::Other, text);
+            let inner = pair
+                .into_inner()
+                .next()
+                .expect("Block instruction is empty");
+            collect_tokens(tokens, inner);
+        }
+        Rule::string_instruction => {
+            let prefix = &span.as_str()[..1];
+            let string = pair
+                .into_inner()
+                .next()
+                .expect("String instruction is empty");
+            let text = match string.as_str().strip_prefix('{') {
+                Some(braced) => {
+                    let words = braced.strip_suffix('}').expect("Unterminated string");
+                    format!("{}{{{}}}", prefix, collapse_words(words))
+                }
+                None => format!("{}{}", prefix, string.as_str()),
+            };
+            push(span.start(), span.end(), TokenKind::Other, text);
+        }
+        Rule::basic_instruction => {
+            let kind = match span.as_str() {
+                "(" | "[" => TokenKind::Open,
+                ")" | "]" => TokenKind::Close,
+                "|" => TokenKind::Else,
+                _ => TokenKind::Other,
+            };
+            push(span.start(), span.end(), kind, span.as_str().to_owned());
+        }
+        _ => panic!("Unexpected token while formatting: {:?}", pair.as_rule()),
+    }
+}
+
+/// Collapses whitespace the same way as subroutine names and string
+/// arguments are read by the parser.
+fn collapse_words(s: &str) -> String {
+    s.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// Splits the whitespace and comments between two tokens into pieces.
+fn gap_pieces(pieces: &mut Vec<Piece>, gap: &str) {
+    let mut rest = gap;
+    while let Some(c) = rest.chars().next() {
+        let len = if rest.starts_with("//") {
+            let len = rest.find(&['\n', '\r'][..]).unwrap_or(rest.len());
+            pieces.push(Piece::Comment(rest[..len].trim_end().to_owned()));
+            len
+        } else if rest.starts_with("/*") {
+            let len = rest.find("*/").map_or(rest.len(), |idx| idx + 2);
+            pieces.push(Piece::Comment(rest[..len].to_owned()));
+            len
+        } else {
+            pieces.push(if c == '\n' {
+                Piece::Newline
+            } else {
+                Piece::Space
+            });
+            c.len_utf8()
+        };
+        rest = &rest[len..];
+    }
+}
+
+fn render(pieces: &[Piece]) -> String {
+    let mut ret = String::new();
+    let mut line = String::new();
+    let mut line_number = 0;
+    // Line number of each unclosed bracket.
+    let mut open_lines: Vec<usize> = vec![];
+    let mut newlines = 0;
+    let mut space = false;
+    for (idx, piece) in pieces.iter().enumerate() {
+        let (kind, text) = match piece {
+            Piece::Newline => {
+                newlines += 1;
+                continue;
+            }
+            Piece::Space => {
+                space = true;
+                continue;
+            }
+            Piece::Token(kind, text) => (*kind, text),
+            Piece::Comment(text) => (TokenKind::Other, text),
+        };
+        if newlines > 0 && !line.is_empty() {
+            finish_line(&mut ret, &mut line);
+            line_number += 1;
+            if newlines > 1 {
+                ret.push('\n');
+            }
+        }
+        if line.is_empty() {
+            line.push_str(&INDENT.repeat(line_indent(&open_lines, &pieces[idx..])));
+        } else if space {
+            line.push(' ');
+        }
+        line.push_str(text);
+        newlines = 0;
+        space = false;
+        match kind {
+            TokenKind::Open => open_lines.push(line_number),
+            TokenKind::Close => drop(open_lines.pop()),
+            TokenKind::Else | TokenKind::Other => (),
+        }
+    }
+    if !line.is_empty() {
+        finish_line(&mut ret, &mut line);
+    }
+    ret
+}
+
+/// Returns the indentation of a line, given the line number of each unclosed
+/// bracket at the start of the line and the pieces that make up the line.
+/// Each line with unclosed brackets adds one level of indentation, no matter
+/// how many brackets it opens. Closing brackets at the start of the line, and
+/// `|` if there are none, are outdented.
+fn line_indent(open_lines: &[usize], line: &[Piece]) -> usize {
+    let mut open_lines = open_lines;
+    let mut outdent_else = true;
+    for piece in line {
+        match piece {
+            Piece::Space => (),
+            Piece::Token(TokenKind::Close, _) => {
+                open_lines = &open_lines[..open_lines.len().saturating_sub(1)];
+                outdent_else = false;
+            }
+            Piece::Token(TokenKind::Else, _) if outdent_else => {
+                open_lines = &open_lines[..open_lines.len().saturating_sub(1)];
+                break;
+            }
+            _ => break,
+        }
+    }
+    let mut indent_lines = open_lines.to_vec();
+    indent_lines.dedup();
+    indent_lines.len()
+}
+
+fn finish_line(ret: &mut String, line: &mut String) {
+    ret.push_str(line.trim_end());
+    ret.push('\n');
+    line.clear();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::format;
+
+    #[test]
+    fn test_format() {
+        assert_eq!(
+            format("@  a   b{ o   !{ x   y }}\n\n\n  !{a  b}").unwrap(),
+            "@ a b { o !{x y}}\n\n!{a b}\n",
+        );
+        assert_eq!(
+            format("e(|x\n<(|\nnx // true\n|n)\n  |n)").unwrap(),
+            "e(|x\n    <(|\n        nx // true\n    |n)\n|n)\n",
+        );
+        assert_eq!(
+            format("[\ne[\n  o\n])x\n/* a\n b */ x").unwrap(),
+            "[\n    e[\n        o\n])x\n/* a\n b */ x\n",
+        );
+        // Brackets opened on the same line only add one level of indentation.
+        assert_eq!(format("(x(x\no\n)\n)").unwrap(), "(x(x\n    o\n    )\n)\n");
+        assert_eq!(format("@a /* c */ {\n}").unwrap(), "@ a /* c */ {\n}\n");
+        assert_eq!(format("#{ lib.mt }f {o}").unwrap(), "#{lib.mt}f {o}\n");
+    }
+
+    #[test]
+    fn test_format_examples() {
+        for entry in std::fs::read_dir("examples").unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().and_then(|ext| ext.to_str()) != Some("mt") {
+                continue;
+            }
+            let source = std::fs::read_to_string(&path).unwrap();
+            let formatted = format(&source).unwrap();
+            assert_eq!(format(&formatted).unwrap(), formatted, "{:?}", path);
+            let encode = |source: &str| {
+                let program = crate::metatape::parse_str(source).unwrap();
+                crate::metatape::golf_encode(&program)
+            };
+            assert_eq!(encode(&source), encode(&formatted), "{:?}", path);
+        }
+    }
+}
@@ -0,0 +1,234 @@
+//! Golf encoding, as described in the README.
+//!
+//! Golf-encoded programs are a sequence of nibbles (high nibble first). Most
+//! instructions are a single nibble; the rest are escaped with `f`.
+//! Subroutine names are stored as raw bytes, two nibbles each.
+
+use super::{parse_error, ParseError, ParseErrorKind};
+use crate::metatape::program::{Instruction, InstructionSeq, Program};
+
+/// Characters that are encoded as a single nibble, indexed by nibble value.
+const NIBBLE_CHARS: [char; 15] = [
+    '.', '|', '(', ')', '<', '>', 'e', 'x', 'i', 'o', '[', ']', '{', '}', 'n',
+];
+
+const ESCAPE: u8 = 0xf;
+
+/// Decodes a golf-encoded program into ASCII Metatape source code.
+///
+/// Errors refer to positions in the hexadecimal representation of the
+/// program, one character per nibble.
+pub fn decode(bytes: &[u8]) -> Result<String, ParseError> {
+    let hex: String = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+    let mut decoder = Decoder {
+        hex: &hex,
+        nibbles: bytes.iter().flat_map(|b| vec![b >> 4, b & 0xf]).collect(),
+        idx: 0,
+    };
+    let mut ret = String::new();
+    while let Some(nibble) = decoder.next_nibble() {
+        if nibble != ESCAPE {
+            ret.push(NIBBLE_CHARS[nibble as usize]);
+            continue;
+        }
+        let start = decoder.idx - 1;
+        match decoder.next_nibble() {
+            Some(0x0) => ret.push('?'),
+            Some(0x1) => ret.push('h'),
+            Some(0x2) => {
+                ret.push('@');
+                ret.push(decoder.next_name_char()?);
+                ret.push('{');
+            }
+            Some(0x3) => {
+                ret.push('!');
+                ret.push(decoder.next_name_char()?);
+            }
+            Some(0x4) => ret.push('f'),
+            Some(0x5) => {
+                ret.push_str("%{");
+                ret.push_str(&decoder.next_string_until(b'}')?);
+                ret.push('}');
+            }
+            Some(0x6) => {
+                ret.push_str("^{");
+                ret.push_str(&decoder.next_string_until(b'}')?);
+                ret.push('}');
+            }
+            Some(0xa) => {
+                ret.push('@');
+                ret.push_str(&decoder.next_string_until(b'{')?);
+                ret.push('{');
+            }
+            Some(0xb) => {
+                ret.push_str("!{");
+                ret.push_str(&decoder.next_string_until(b'}')?);
+                ret.push('}');
+            }
+            Some(_) => decoder.error(start, ParseErrorKind::UnknownEscape)?,
+            None => decoder.error(start, ParseErrorKind::IncompleteEscape)?,
+        }
+    }
+    Ok(ret)
+}
+
+struct Decoder<'a> {
+    hex: &'a str,
+    nibbles: Vec<u8>,
+    idx: usize,
+}
+
+impl Decoder<'_> {
+    fn next_nibble(&mut self) -> Option<u8> {
+        let ret = self.nibbles.get(self.idx).copied();
+        if ret.is_some() {
+            self.idx += 1;
+        }
+        ret
+    }
+
+    fn next_byte(&mut self) -> Result<u8, ParseError> {
+        let start = self.idx;
+        match (self.next_nibble(), self.next_nibble()) {
+            (Some(hi), Some(lo)) => Ok((hi << 4) | lo),
+            _ => self.error(start, ParseErrorKind::IncompleteString),
+        }
+    }
+
+    /// Reads a single-character subroutine name, as used by `f2` and `f3`.
+    fn next_name_char(&mut self) -> Result<char, ParseError> {
+        let start = self.idx;
+        let byte = self.next_byte()?;
+        if byte.is_ascii_graphic() && !b"{}/".contains(&byte) {
+            Ok(byte as char)
+        } else {
+            self.error(start, ParseErrorKind::InvalidNameChar)
+        }
+    }
+
+    /// Reads a string terminated by the given byte, as used by `f5`, `f6`,
+    /// `fa`, and `fb`. The terminator is consumed but not returned.
+    fn next_string_until(&mut self, terminator: u8) -> Result<String, ParseError> {
+        let start = self.idx;
+        let mut bytes = vec![];
+        loop {
+            match self.next_byte()? {
+                byte if byte == terminator => break,
+                byte => bytes.push(byte),
+            }
+        }
+        String::from_utf8(bytes).or_else(|_| self.error(start, ParseErrorKind::InvalidUtf8))
+    }
+
+    fn error<T>(&self, start: usize, kind: ParseErrorKind) -> Result<T, ParseError> {
+        let end = self.idx.max(start + 1).min(self.hex.len());
+        parse_error(
+            pest::Span::new(self.hex, start.min(end), end).unwrap(),
+            kind,
+        )
+    }
+}
+
+/// Encodes a program using golf encoding. Subroutine definitions are placed
+/// after the main program, sorted by name.
+pub fn encode(program: &Program) -> Vec<u8> {
+    let mut nibbles = vec![];
+    encode_seq(&mut nibbles, &program.instructions);
+    let mut names: Vec<&String> = program.subroutines.keys().collect();
+    names.sort();
+    for name in names {
+        if let Some(c) = single_byte_name(name) {
+            nibbles.extend_from_slice(&[ESCAPE, 0x2]);
+            push_byte(&mut nibbles, c);
+        } else {
+            nibbles.extend_from_slice(&[ESCAPE, 0xa]);
+            name.bytes().for_each(|b| push_byte(&mut nibbles, b));
+            push_byte(&mut nibbles, b'{');
+        }
+        encode_seq(&mut nibbles, &program.subroutines[name]);
+        nibbles.push(0xd);
+    }
+    // Pad with a no-op if necessary.
+    if nibbles.len() % 2 == 1 {
+        nibbles.push(0x0);
+    }
+    nibbles
+        .chunks(2)
+        .map(|pair| (pair[0] << 4) | pair[1])
+        .collect()
+}
+
+fn encode_seq(nibbles: &mut Vec<u8>, instructions: &InstructionSeq) {
+    for (_, instruction) in instructions {
+        encode_instruction(nibbles, instruction);
+    }
+}
+
+fn encode_instruction(nibbles: &mut Vec<u8>, instruction: &Instruction) {
+    match instruction {
+        Instruction::Nop => nibbles.push(0x0),
+        Instruction::Else(_) => nibbles.push(0x1),
+        Instruction::If(_) => nibbles.push(0x2),
+        Instruction::EndIf => nibbles.push(0x3),
+        Instruction::Left => nibbles.push(0x4),
+        Instruction::Right => nibbles.push(0x5),
+        Instruction::Enter => nibbles.push(0x6),
+        Instruction::Exit => nibbles.push(0x7),
+        Instruction::Input => nibbles.push(0x8),
+        Instruction::Output => nibbles.push(0x9),
+        Instruction::Loop => nibbles.push(0xa),
+        Instruction::EndLoop(_) => nibbles.push(0xb),
+        Instruction::Null => nibbles.push(0xe),
+
+        Instruction::Block(block) => {
+            nibbles.push(0xc);
+            encode_seq(nibbles, block);
+            nibbles.push(0xd);
+        }
+
+        Instruction::Random => nibbles.extend_from_slice(&[ESCAPE, 0x0]),
+        Instruction::Halt => nibbles.extend_from_slice(&[ESCAPE, 0x1]),
+        Instruction::Fork(block) => {
+            nibbles.extend_from_slice(&[ESCAPE, 0x4]);
+            if block.len() == 1 {
+                encode_seq(nibbles, block);
+            } else {
+                nibbles.push(0xc);
+                encode_seq(nibbles, block);
+                nibbles.push(0xd);
+            }
+        }
+        Instruction::IOMode(mode) => {
+            nibbles.extend_from_slice(&[ESCAPE, 0x5]);
+            mode.to_string().bytes().for_each(|b| push_byte(nibbles, b));
+            push_byte(nibbles, b'}');
+        }
+        Instruction::Seek(path) => {
+            nibbles.extend_from_slice(&[ESCAPE, 0x6]);
+            path.to_string().bytes().for_each(|b| push_byte(nibbles, b));
+            push_byte(nibbles, b'}');
+        }
+        Instruction::Call(name, _) => {
+            if let Some(c) = single_byte_name(name) {
+                nibbles.extend_from_slice(&[ESCAPE, 0x3]);
+                push_byte(nibbles, c);
+            } else {
+                nibbles.extend_from_slice(&[ESCAPE, 0xb]);
+                name.bytes().for_each(|b| push_byte(nibbles, b));
+                push_byte(nibbles, b'}');
+            }
+        }
+    }
+}
+
+fn single_byte_name(name: &str) -> Option<u8> {
+    match name.as_bytes() {
+        [c] => Some(*c),
+        _ => None,
+    }
+}
+
+fn push_byte(nibbles: &mut Vec<u8>, byte: u8) {
+    nibbles.push(byte >> 4);
+    nibbles.push(byte & 0xf);
+}
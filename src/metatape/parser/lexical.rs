@@ -1,49 +1,125 @@
 #![allow(dead_code)]
 
 use pest::Parser;
-use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::path::PathBuf;
 use std::rc::Rc;
 
-use super::{parse_error, Grammar, ParseError, Rule, SemanticParser, TokenPair};
-use crate::metatape::program::{Instruction, InstructionSeq, Program, Subroutines};
+use super::{
+    decode, parse_error, Grammar, Loader, ParseError, ParseErrorKind, Rule, SemanticParser,
+    SourceFormat, Span, TokenPair,
+};
+use crate::metatape::program::{Instruction, InstructionSeq};
 
 impl SemanticParser {
-    pub(super) fn parse_semantics(self) -> Result<Program, ParseError> {
-        let main_pair = Grammar::parse(Rule::main, &self.source_string)?
+    pub(super) fn parse_semantics(
+        &self,
+        loader: &mut Loader,
+    ) -> Result<InstructionSeq, ParseError> {
+        let main_pair = Grammar::parse(Rule::main, &self.source_string)
+            .map_err(ParseError::from_pest)?
             .next()
             .expect("No main token");
         let mut instructions: InstructionSeq = vec![];
-        let mut subroutines: Subroutines = HashMap::new();
         for pair in main_pair.into_inner() {
             match pair.as_rule() {
                 Rule::EOI => (),
                 Rule::instruction => {
-                    instructions.push((pair.as_span().start(), self.tokenize_instruction(pair)?))
+                    let source_idx = self.offset + pair.as_span().start();
+                    if let Some(instruction) = self.recover(self.tokenize_instruction(pair)) {
+                        instructions.push((source_idx, instruction));
+                    }
                 }
                 Rule::subroutine_def => {
                     let span = pair.as_span();
-                    let (name, mut sub_instructions) = self.tokenize_subroutine(pair)?;
-                    self.resolve_jumps(&mut sub_instructions)?;
-                    if subroutines.contains_key(&name) {
-                        parse_error(
-                            span,
-                            format!("Duplicate subroutine definition with name {:?}", name),
-                        )?;
+                    let (name, sub_instructions) = self.tokenize_subroutine(pair);
+                    match loader.subroutines.entry(name) {
+                        Entry::Occupied(entry) => {
+                            let kind = ParseErrorKind::DuplicateSubroutine(entry.key().clone());
+                            self.recover(parse_error::<()>(span, kind));
+                        }
+                        Entry::Vacant(entry) => {
+                            let source_idx = self.offset + span.start();
+                            loader.definitions.insert(entry.key().clone(), source_idx);
+                            entry.insert(Rc::new(sub_instructions));
+                        }
                     }
-                    subroutines.insert(name, Rc::new(sub_instructions));
+                }
+                Rule::load => {
+                    let result = self.load(pair, loader);
+                    self.recover(result);
                 }
                 _ => panic!("Invalid token inside main: {:?}", pair.as_rule()),
             }
         }
-        self.resolve_jumps(&mut instructions)?;
-        Ok(Program {
-            source: self.source_string,
-            instructions: Rc::new(instructions),
-            subroutines,
-        })
+        self.resolve_jumps(&mut instructions);
+        let with_path = |err: ParseError| match &self.path {
+            Some(path) => err.with_path(&path.display().to_string()),
+            None => err,
+        };
+        let mut errors = self.errors.take();
+        errors.sort_by_key(|err| err.span.as_ref().map(|span| span.start));
+        loader.errors.extend(errors.into_iter().map(with_path));
+        loader
+            .warnings
+            .extend(self.warnings.take().into_iter().map(with_path));
+        Ok(instructions)
+    }
+
+    /// Records an error so that parsing can continue, returning the value if
+    /// there was no error.
+    pub(super) fn recover<T>(&self, result: Result<T, ParseError>) -> Option<T> {
+        result
+            .map_err(|err| self.errors.borrow_mut().push(err))
+            .ok()
     }
 
-    fn tokenize_subroutine(&self, pair: TokenPair) -> Result<(String, InstructionSeq), ParseError> {
+    /// Loads the subroutines defined in another file. Top-level instructions
+    /// in that file are ignored.
+    fn load(&self, pair: TokenPair, loader: &mut Loader) -> Result<(), ParseError> {
+        let span = pair.as_span();
+        let relative_path = pair
+            .into_inner()
+            .next()
+            .expect("Load contains no path")
+            .as_str()
+            .trim();
+        let path = match self.path.as_ref().and_then(|path| path.parent()) {
+            Some(dir) => dir.join(relative_path),
+            None => PathBuf::from(relative_path),
+        };
+        let load_failed = |err: std::io::Error| {
+            let kind = ParseErrorKind::LoadFailed {
+                path: relative_path.to_owned(),
+                reason: err.to_string(),
+            };
+            ParseError::new(kind, Some(Span::from_pest(span)))
+        };
+        let canonical_path = path.canonicalize().map_err(load_failed)?;
+        if loader.loading.contains(&canonical_path) {
+            return parse_error(span, ParseErrorKind::CyclicLoad(relative_path.to_owned()));
+        }
+        if loader.loaded.contains(&canonical_path) {
+            return Ok(());
+        }
+        let source = std::fs::read(&path).map_err(load_failed)?;
+        // Errors inside the loaded file belong to that file, so they go
+        // directly to the loader.
+        let format = SourceFormat::detect(&source);
+        match decode(source, format) {
+            Ok(source_string) => {
+                if let Err(err) = loader.parse_file(source_string, Some(path)) {
+                    loader.errors.push(err);
+                }
+            }
+            Err(err) => loader
+                .errors
+                .push(err.with_path(&path.display().to_string())),
+        }
+        Ok(())
+    }
+
+    fn tokenize_subroutine(&self, pair: TokenPair) -> (String, InstructionSeq) {
         let mut parts = pair.into_inner();
         let name = parts
             .next()
@@ -54,26 +130,29 @@ impl SemanticParser {
                 .next()
                 .expect("Subroutine definition contains no body"),
         );
-        return Ok((name.to_owned(), block_arg?));
+        (name.to_owned(), block_arg)
     }
 
-    fn tokenize_block(&self, pair: TokenPair) -> Result<InstructionSeq, ParseError> {
+    fn tokenize_block(&self, pair: TokenPair) -> InstructionSeq {
         let mut ret: InstructionSeq = vec![];
         for inner_pair in pair.into_inner() {
             let span = inner_pair.as_span();
-            ret.push((
-                span.start(),
-                match inner_pair.as_rule() {
-                    Rule::instruction => self.tokenize_instruction(inner_pair),
-                    _ => parse_error(
-                        span,
-                        format!("Invalid token inside block: {:?}", inner_pair.as_rule()),
-                    ),
-                }?,
-            ));
+            let instruction = match inner_pair.as_rule() {
+                Rule::instruction => self.tokenize_instruction(inner_pair),
+                _ => parse_error(
+                    span,
+                    ParseErrorKind::Syntax(format!(
+                        "Invalid token inside block: {:?}",
+                        inner_pair.as_rule()
+                    )),
+                ),
+            };
+            if let Some(instruction) = self.recover(instruction) {
+                ret.push((self.offset + span.start(), instruction));
+            }
         }
-        self.resolve_jumps(&mut ret)?;
-        Ok(ret)
+        self.resolve_jumps(&mut ret);
+        ret
     }
 
     fn tokenize_string(&self, pair: TokenPair) -> String {
@@ -93,34 +172,33 @@ impl SemanticParser {
             .expect("Instruction token contained no inner token");
         let span = inner_pair.as_span();
         match inner_pair.as_rule() {
-            Rule::block => Ok(Instruction::Block(Rc::new(
-                self.tokenize_block(inner_pair)?,
-            ))),
-            Rule::block_instruction => Ok(self.tokenize_block_instruction(inner_pair)?),
+            Rule::block => Ok(Instruction::Block(Rc::new(self.tokenize_block(inner_pair)))),
+            Rule::block_instruction => Ok(self.tokenize_block_instruction(inner_pair)),
             Rule::string_instruction => self.tokenize_string_instruction(inner_pair),
             Rule::basic_instruction => self.tokenize_basic_instruction(inner_pair),
-            _ => Err(format!(
+            _ => Err(ParseErrorKind::Syntax(format!(
                 "Invalid token inside instruction: {:?}",
                 inner_pair.as_rule()
-            )),
+            ))),
         }
-        .or_else(|error_message| parse_error(span, error_message))
+        .or_else(|kind| parse_error(span, kind))
     }
 
-    fn tokenize_block_instruction(&self, pair: TokenPair) -> Result<Instruction, ParseError> {
+    fn tokenize_block_instruction(&self, pair: TokenPair) -> Instruction {
         let instruction_char = pair
             .as_str()
             .chars()
             .next()
             .expect("Block instruction contains no instruction");
+        self.check_case(pair.as_span());
         let block_arg = self.tokenize_block(pair);
-        Ok(match instruction_char {
-            'f' => Instruction::Fork(Rc::new(block_arg?)),
+        match instruction_char.to_ascii_lowercase() {
+            'f' => Instruction::Fork(Rc::new(block_arg)),
             _ => panic!("Unrecognized block instruction: {:#?}", instruction_char),
-        })
+        }
     }
 
-    fn tokenize_string_instruction(&self, pair: TokenPair) -> Result<Instruction, String> {
+    fn tokenize_string_instruction(&self, pair: TokenPair) -> Result<Instruction, ParseErrorKind> {
         let instruction_char = pair
             .as_str()
             .chars()
@@ -131,14 +209,23 @@ impl SemanticParser {
                 .next()
                 .expect("String instruction contains no argument"),
         );
-        Ok(match instruction_char {
-            '!' => Instruction::Call,
+        match instruction_char {
+            '!' => Ok(Instruction::Call(string_arg, 0)),
+            '%' => Ok(Instruction::IOMode(
+                string_arg.parse().map_err(ParseErrorKind::InvalidIOMode)?,
+            )),
+            '^' => Ok(Instruction::Seek(
+                string_arg
+                    .parse()
+                    .map_err(ParseErrorKind::InvalidSeekPath)?,
+            )),
             _ => panic!("Unrecognized string instruction: {:#?}", instruction_char),
-        }(string_arg.to_owned()))
+        }
     }
 
-    fn tokenize_basic_instruction(&self, pair: TokenPair) -> Result<Instruction, String> {
-        match pair.as_str() {
+    fn tokenize_basic_instruction(&self, pair: TokenPair) -> Result<Instruction, ParseErrorKind> {
+        self.check_case(pair.as_span());
+        match pair.as_str().to_ascii_lowercase().as_str() {
             "." => Ok(Instruction::Nop),
             "<" => Ok(Instruction::Left),
             ">" => Ok(Instruction::Right),
@@ -154,8 +241,34 @@ impl SemanticParser {
             "i" => Ok(Instruction::Input),
             "o" => Ok(Instruction::Output),
             "h" => Ok(Instruction::Halt),
-            "@" => Err("Subroutines cannot be defined inside a subroutine or block".to_owned()),
-            _ => Err(format!("Unrecognized instruction: {:?}", pair.as_str())),
+            "@" => Err(ParseErrorKind::SubroutineInBlock),
+            "#" => Err(ParseErrorKind::LoadInBlock),
+            _ => Err(ParseErrorKind::UnknownInstruction(pair.as_str().to_owned())),
+        }
+    }
+
+    /// In strict mode, warns if the case of a letter instruction differs from
+    /// the case of the first letter instruction in the file.
+    fn check_case(&self, span: pest::Span) {
+        if !self.options.strict {
+            return;
+        }
+        let c = span.as_str().chars().next().expect("Empty instruction");
+        if !c.is_ascii_alphabetic() {
+            return;
+        }
+        match self.first_letter.get() {
+            None => self.first_letter.set(Some(c)),
+            Some(first) if first.is_ascii_uppercase() != c.is_ascii_uppercase() => {
+                let kind = ParseErrorKind::MixedCase {
+                    instruction: c,
+                    first,
+                };
+                if let Err(warning) = parse_error::<()>(span, kind) {
+                    self.warnings.borrow_mut().push(warning);
+                }
+            }
+            Some(_) => (),
         }
     }
 }
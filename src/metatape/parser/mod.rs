@@ -1,7 +1,14 @@
-use pest::error::ErrorVariant::CustomError as CustomPestError;
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
 
-use super::program::Program;
+use super::program::{InstructionSeq, Program, SourceFile, Subroutines};
 
+mod calls;
+mod error;
+pub mod format;
+pub mod golf;
 mod lexical;
 mod syntactic;
 
@@ -9,20 +16,401 @@ mod syntactic;
 #[grammar = "metatape/parser/grammar.pest"]
 struct Grammar;
 
-pub type ParseError = pest::error::Error<Rule>;
+pub use error::{ParseError, ParseErrorKind, Span};
 type TokenPair<'a> = pest::iterators::Pair<'a, Rule>;
 
+/// Encoding of a Metatape source file.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SourceFormat {
+    /// Plain text, as described in the README.
+    Ascii,
+    /// Golf encoding, as described in the README.
+    Golf,
+}
+
+impl SourceFormat {
+    /// Guesses the encoding of a source file. ASCII programs are text, so
+    /// anything that is not valid UTF-8 or contains control characters other
+    /// than whitespace is assumed to be golf-encoded.
+    pub fn detect(source: &[u8]) -> Self {
+        match std::str::from_utf8(source) {
+            Ok(s) if !s.chars().any(|c| c.is_control() && !c.is_whitespace()) => Self::Ascii,
+            _ => Self::Golf,
+        }
+    }
+}
+
+/// Options that control how programs are parsed.
+#[derive(Debug, Default, Clone)]
+pub struct ParseOptions {
+    /// Format of the main source file, or `None` to detect it automatically.
+    /// Loaded files are always detected automatically.
+    pub format: Option<SourceFormat>,
+    /// Whether to warn about questionable style, such as mixing uppercase
+    /// and lowercase instructions, and about code that is probably a mistake
+    /// (see `Program::lint()`).
+    pub strict: bool,
+    /// Whether to allow calls to subroutines that are not defined, e.g.
+    /// because they will be added to the program before it is run. Calls to
+    /// subroutines that are still undefined fail at runtime.
+    pub allow_undefined_calls: bool,
+}
+
 struct SemanticParser {
     source_string: String,
+    options: ParseOptions,
+    /// First letter instruction in the file, used to check case consistency.
+    first_letter: Cell<Option<char>>,
+    /// Errors that parsing has recovered from.
+    errors: RefCell<Vec<ParseError>>,
+    warnings: RefCell<Vec<ParseError>>,
+    /// Position of the start of `source_string` in the program's combined
+    /// source.
+    offset: usize,
+    /// Path of the file being parsed, if any.
+    path: Option<PathBuf>,
+}
+
+impl SemanticParser {
+    /// Returns the span of the single character at the given position in the
+    /// program's combined source.
+    fn char_span(&self, source_idx: usize) -> pest::Span<'_> {
+        let idx = source_idx - self.offset;
+        pest::Span::new(&self.source_string, idx, idx + 1).unwrap()
+    }
 }
 
-fn parse_error<T>(span: pest::Span, message: String) -> Result<T, ParseError> {
-    Err(pest::error::Error::new_from_span(
-        CustomPestError { message },
-        span,
-    ))
+/// State shared between all of the files that make up a program.
+#[derive(Default)]
+struct Loader {
+    options: ParseOptions,
+    source: String,
+    files: Vec<SourceFile>,
+    subroutines: Subroutines,
+    /// Position in the combined source of the definition of each subroutine.
+    definitions: HashMap<String, usize>,
+    errors: Vec<ParseError>,
+    warnings: Vec<ParseError>,
+    /// Canonical paths of files that are currently being parsed, used to
+    /// detect cyclic loads.
+    loading: Vec<PathBuf>,
+    /// Canonical paths of files that have already been loaded, so that each
+    /// file is only loaded once.
+    loaded: HashSet<PathBuf>,
 }
 
-pub(super) fn parse(source_string: String) -> Result<Program, ParseError> {
-    SemanticParser { source_string }.parse_semantics()
+impl Loader {
+    /// Parses a file, adding its subroutines to the program and returning its
+    /// top-level instructions. Errors that parsing can recover from are added
+    /// to `errors`; any other error is returned.
+    fn parse_file(
+        &mut self,
+        source_string: String,
+        path: Option<PathBuf>,
+    ) -> Result<InstructionSeq, ParseError> {
+        let offset = self.source.len();
+        self.source.push_str(&source_string);
+        self.files.push(SourceFile {
+            path: path.as_ref().map(|path| path.display().to_string()),
+            start: offset,
+        });
+        let canonical_path = path.as_ref().and_then(|path| path.canonicalize().ok());
+        if let Some(canonical_path) = &canonical_path {
+            self.loading.push(canonical_path.clone());
+            self.loaded.insert(canonical_path.clone());
+        }
+        let display_path = path.as_ref().map(|path| path.display().to_string());
+        let ret = SemanticParser {
+            source_string,
+            options: self.options.clone(),
+            first_letter: Cell::new(None),
+            errors: RefCell::new(vec![]),
+            warnings: RefCell::new(vec![]),
+            offset,
+            path,
+        }
+        .parse_semantics(self);
+        if canonical_path.is_some() {
+            self.loading.pop();
+        }
+        // Errors from loaded files already have their own path.
+        ret.map_err(|err| match display_path {
+            Some(path) if err.path().is_none() => err.with_path(&path),
+            _ => err,
+        })
+    }
+}
+
+fn parse_error<T>(span: pest::Span, kind: ParseErrorKind) -> Result<T, ParseError> {
+    Err(ParseError::new(kind, Some(Span::from_pest(span))))
+}
+
+/// Parses a program, returning all of the errors found if there are any. Files
+/// loaded by the program are found relative to `path`, or relative to the
+/// current directory if there is no path.
+pub(super) fn parse(
+    source: Vec<u8>,
+    options: &ParseOptions,
+    path: Option<&Path>,
+) -> Result<Program, Vec<ParseError>> {
+    let format = options
+        .format
+        .unwrap_or_else(|| SourceFormat::detect(&source));
+    let source_string = decode(source, format).map_err(|err| match path {
+        Some(path) => err.with_path(&path.display().to_string()),
+        None => err,
+    });
+    let source_string = source_string.map_err(|err| vec![err])?;
+    let mut loader = Loader {
+        options: options.clone(),
+        ..Loader::default()
+    };
+    let mut instructions = loader
+        .parse_file(source_string, path.map(Path::to_owned))
+        .unwrap_or_else(|err| {
+            loader.errors.push(err);
+            vec![]
+        });
+    // Subroutines may be missing because a file could not be parsed or
+    // loaded, in which case calls to them aren't errors of their own.
+    let all_files_parsed = !loader.errors.iter().any(|err| {
+        matches!(
+            err.kind,
+            ParseErrorKind::Syntax(_) | ParseErrorKind::LoadFailed { .. }
+        )
+    });
+    if !options.allow_undefined_calls && all_files_parsed {
+        loader.check_calls(&instructions);
+    }
+    if !loader.errors.is_empty() {
+        return Err(loader.errors);
+    }
+    let call_names = loader.assign_call_ids(&mut instructions);
+    let mut program = Program {
+        source: loader.source,
+        files: loader.files,
+        subroutines: loader.subroutines,
+        definitions: loader.definitions,
+        instructions: Rc::new(instructions),
+        call_names,
+        linked_calls: vec![],
+        warnings: loader.warnings,
+    };
+    program.link();
+    if options.strict {
+        let warnings = program.lint();
+        program.warnings.extend(warnings);
+    }
+    Ok(program)
+}
+
+fn decode(source: Vec<u8>, format: SourceFormat) -> Result<String, ParseError> {
+    Ok(match format {
+        SourceFormat::Ascii => String::from_utf8(source).or_else(|err| {
+            let idx = err.utf8_error().valid_up_to();
+            let lossy = String::from_utf8_lossy(err.as_bytes()).into_owned();
+            parse_error(
+                pest::Span::new(&lossy, idx, idx).unwrap(),
+                ParseErrorKind::InvalidUtf8,
+            )
+        })?,
+        SourceFormat::Golf => golf::decode(&source)?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ParseErrorKind::{self, *};
+    use super::ParseOptions;
+    use crate::metatape::parse_str;
+    use crate::metatape::program::Instruction;
+    use std::rc::Rc;
+
+    /// Asserts that parsing fails with the given kind of error at the given
+    /// line and column.
+    fn assert_parse_error(source: &str, kind: ParseErrorKind, line_col: (usize, usize)) {
+        assert_parse_errors(source, &[(kind, line_col)]);
+    }
+
+    fn assert_parse_errors(source: &str, expected: &[(ParseErrorKind, (usize, usize))]) {
+        let errors = parse_str(source).expect_err("expected a parse error");
+        let actual: Vec<_> = errors
+            .into_iter()
+            .map(|err| (err.kind, err.span.expect("expected a span").start_line_col))
+            .collect();
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn test_unmatched_if() {
+        assert_parse_error("e(x", UnmatchedIf, (1, 2));
+        assert_parse_error("((o)", UnmatchedIf, (1, 1));
+        assert_parse_error("[(]", UnmatchedIf, (1, 2));
+    }
+
+    #[test]
+    fn test_unmatched_else() {
+        assert_parse_error("o|o", UnmatchedElse, (1, 2));
+        assert_parse_error("(o)|o", UnmatchedElse, (1, 4));
+    }
+
+    #[test]
+    fn test_unmatched_else_is_only_reported_once() {
+        assert_parse_error("o|o)", UnmatchedElse, (1, 2));
+        assert_parse_error("o|o", UnmatchedElse, (1, 2));
+    }
+
+    #[test]
+    fn test_unmatched_endif() {
+        assert_parse_error("ex)", UnmatchedEndIf, (1, 3));
+    }
+
+    #[test]
+    fn test_unmatched_loop() {
+        assert_parse_error("\n  [exio", UnmatchedLoop, (2, 3));
+        // The error is at the `[`, not at the position in the source that
+        // has the same index as the `[` has in the list of instructions.
+        assert_parse_error("ex // [\n  o [o", UnmatchedLoop, (2, 5));
+        assert_parse_error("[ex] o [o", UnmatchedLoop, (1, 8));
+    }
+
+    #[test]
+    fn test_unmatched_endloop() {
+        assert_parse_error("exio]", UnmatchedEndLoop, (1, 5));
+        assert_parse_error("[o]]", UnmatchedEndLoop, (1, 4));
+    }
+
+    #[test]
+    fn test_duplicate_subroutine() {
+        assert_parse_error(
+            "@ a { o }\n@ a { i }",
+            DuplicateSubroutine("a".to_owned()),
+            (2, 1),
+        );
+    }
+
+    #[test]
+    fn test_multiple_errors() {
+        assert_parse_errors(
+            // The `(` on line 1 matches the `)` on line 4.
+            "(o]\n@ a { [+ }\n@ a { }\n]) | ((",
+            &[
+                (UnmatchedEndLoop, (1, 3)),
+                (UnmatchedLoop, (2, 7)),
+                (UnknownInstruction("+".to_owned()), (2, 8)),
+                (DuplicateSubroutine("a".to_owned()), (3, 1)),
+                (UnmatchedEndLoop, (4, 1)),
+                (UnmatchedElse, (4, 4)),
+                (UnmatchedIf, (4, 6)),
+                (UnmatchedIf, (4, 7)),
+            ],
+        );
+    }
+
+    #[test]
+    fn test_undefined_subroutine() {
+        let undefined = |name: &str, suggestion: Option<&str>| UndefinedSubroutine {
+            name: name.to_owned(),
+            suggestion: suggestion.map(str::to_owned),
+        };
+        assert_parse_errors(
+            "!{new 1} f{ !x }\n@ new 0 { !{new 0} }\n@ y { {!{new 00} !z} !{Y} }",
+            &[
+                (undefined("new 1", Some("new 0")), (1, 1)),
+                (undefined("x", None), (1, 13)),
+                (undefined("new 00", Some("new 0")), (3, 8)),
+                (undefined("z", None), (3, 18)),
+                (undefined("Y", Some("y")), (3, 22)),
+            ],
+        );
+        let options = ParseOptions {
+            allow_undefined_calls: true,
+            ..ParseOptions::default()
+        };
+        assert!(super::parse(b"!x".to_vec(), &options, None).is_ok());
+    }
+
+    #[test]
+    fn test_undefined_subroutine_after_syntax_error() {
+        // `a` may be defined after the syntax error.
+        assert_parse_errors(
+            "!a @ b",
+            &[(Syntax("expected block or char".to_owned()), (1, 7))],
+        );
+
+        // The same goes for a loaded file.
+        let dir = std::env::temp_dir().join(format!("metatape-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("lib.mt"), "@ a { o }\n@ b\n").unwrap();
+        std::fs::write(dir.join("main.mt"), "#{lib.mt}\n!a !b").unwrap();
+        let main = dir.join("main.mt");
+        let result =
+            crate::metatape::program_from_file(main.to_str().unwrap(), &ParseOptions::default());
+        std::fs::remove_dir_all(&dir).unwrap();
+        let kinds: Vec<_> = result
+            .unwrap_err()
+            .into_iter()
+            .map(|err| err.kind)
+            .collect();
+        assert!(matches!(kinds.as_slice(), [Syntax(_)]), "{:?}", kinds);
+    }
+
+    #[test]
+    fn test_call_ids() {
+        let program = parse_str("@ a { !b f{ !a } }\n@ b { o }\n!b !a").unwrap();
+        let call_ids: Vec<_> = program
+            .instructions
+            .iter()
+            .map(|(_, instruction)| match instruction {
+                Instruction::Call(name, call_id) => {
+                    assert_eq!(program.call_names[*call_id], *name);
+                    *call_id
+                }
+                _ => panic!("expected a call"),
+            })
+            .collect();
+        assert_eq!(call_ids, [0, 1]);
+        let b = program.linked_call(0).unwrap();
+        assert!(Rc::ptr_eq(b, &program.subroutines["b"]));
+        assert!(program.linked_call(2).is_none());
+    }
+
+    #[test]
+    fn test_link_after_parsing() {
+        let options = ParseOptions {
+            allow_undefined_calls: true,
+            ..ParseOptions::default()
+        };
+        let mut program = super::parse(b"!x".to_vec(), &options, None).unwrap();
+        assert!(program.linked_call(0).is_none());
+        program.subroutines.insert("x".to_owned(), Rc::new(vec![]));
+        program.link();
+        assert!(program.linked_call(0).is_some());
+    }
+
+    #[test]
+    fn test_json() {
+        let err = parse_str("o\n(").unwrap_err().remove(0);
+        assert_eq!(
+            err.to_json("error"),
+            r#"{"severity":"error","kind":"UnmatchedIf","message":"No matching 'endif' instruction","path":null,"start":{"offset":2,"line":2,"col":1},"end":{"offset":3,"line":2,"col":2}}"#,
+        );
+        let err = err.with_path("a\\b.mt");
+        assert!(err.to_json("error").contains(r#""path":"a\\b.mt""#));
+    }
+
+    #[test]
+    fn test_valid_programs() {
+        for source in &[
+            "",
+            "(|)",
+            "(|||)",
+            "[(])",
+            "(x|[e(])x)",
+            "@ a { [(]) } !a",
+            "f{eo}",
+        ] {
+            assert!(parse_str(source).is_ok(), "failed to parse {:?}", source);
+        }
+    }
 }
@@ -1,54 +1,53 @@
-use super::{parse_error, ParseError, SemanticParser};
+use super::{parse_error, ParseErrorKind, SemanticParser};
 use crate::metatape::program::{Instruction, InstructionSeq};
 
 impl SemanticParser {
     /// Resolve jump instructions (If, Else, and EndLoop) to their matching
-    /// destinations, throwing a ParseError if one does not have a matching
-    /// counterpart.
-    pub(super) fn resolve_jumps(
-        &self,
-        instructions: &mut InstructionSeq,
-    ) -> Result<(), ParseError> {
-        self.resolve_conditions(instructions)?;
-        self.resolve_loops(instructions)?;
-        Ok(())
+    /// destinations, recording a ParseError for each one that does not have a
+    /// matching counterpart.
+    pub(super) fn resolve_jumps(&self, instructions: &mut InstructionSeq) {
+        self.resolve_conditions(instructions);
+        self.resolve_loops(instructions);
     }
 
-    fn resolve_conditions(&self, instructions: &mut InstructionSeq) -> Result<(), ParseError> {
+    fn resolve_conditions(&self, instructions: &mut InstructionSeq) {
         // Track a stack of IF and ELSE instructions. For each instruction, store a
-        // tuple (source_idx: usize, jump_destination: &mut usize).
-        let mut source_indices: Vec<usize> = vec![];
+        // tuple (source_idx: Option<usize>, jump_destination: &mut usize). The
+        // source index is `None` if an error has already been recorded for the
+        // instruction.
+        let mut source_indices: Vec<Option<usize>> = vec![];
         let mut destination_refs: Vec<&mut usize> = vec![];
         for (idx, (source_idx, instruction)) in instructions.iter_mut().enumerate() {
+            let mut matched = true;
             if let Instruction::Else(_) | Instruction::EndIf = instruction {
                 source_indices.pop();
                 if let Some(jump_destination) = destination_refs.pop() {
                     *jump_destination = idx;
                 } else {
-                    return parse_error(
-                        pest::Span::new(&self.source_string, *source_idx, *source_idx + 1).unwrap(),
-                        "No matching 'if' instruction".to_owned(),
-                    );
+                    // An unmatched ELSE is still pushed onto the stack below,
+                    // so that its ENDIF isn't reported too.
+                    matched = false;
+                    let kind = match instruction {
+                        Instruction::Else(_) => ParseErrorKind::UnmatchedElse,
+                        _ => ParseErrorKind::UnmatchedEndIf,
+                    };
+                    self.recover(parse_error::<()>(self.char_span(*source_idx), kind));
                 }
             }
             if let Instruction::If(ref mut destination) | Instruction::Else(ref mut destination) =
                 instruction
             {
-                source_indices.push(*source_idx);
+                source_indices.push(Some(*source_idx).filter(|_| matched));
                 destination_refs.push(destination);
             }
         }
-        if let Some(source_idx) = source_indices.pop() {
-            parse_error(
-                pest::Span::new(&self.source_string, source_idx, source_idx + 1).unwrap(),
-                "No matching 'endif' instruction".to_owned(),
-            )
-        } else {
-            Ok(())
+        for source_idx in source_indices.into_iter().flatten() {
+            let kind = ParseErrorKind::UnmatchedIf;
+            self.recover(parse_error::<()>(self.char_span(source_idx), kind));
         }
     }
 
-    fn resolve_loops(&self, instructions: &mut InstructionSeq) -> Result<(), ParseError> {
+    fn resolve_loops(&self, instructions: &mut InstructionSeq) {
         let mut source_indices: Vec<usize> = vec![];
         let mut destinations: Vec<usize> = vec![];
         for (idx, (source_idx, instruction)) in instructions.iter_mut().enumerate() {
@@ -57,10 +56,8 @@ impl SemanticParser {
                 if let Some(dest) = destinations.pop() {
                     *destination = dest;
                 } else {
-                    return parse_error(
-                        pest::Span::new(&self.source_string, *source_idx, *source_idx + 1).unwrap(),
-                        "No matching 'loop' instruction".to_owned(),
-                    );
+                    let kind = ParseErrorKind::UnmatchedEndLoop;
+                    self.recover(parse_error::<()>(self.char_span(*source_idx), kind));
                 }
             }
             if let Instruction::Loop = instruction {
@@ -68,13 +65,9 @@ impl SemanticParser {
                 destinations.push(idx);
             }
         }
-        if let Some(source_idx) = destinations.pop() {
-            parse_error(
-                pest::Span::new(&self.source_string, source_idx, source_idx + 1).unwrap(),
-                "No matching 'endloop' instruction".to_owned(),
-            )
-        } else {
-            Ok(())
+        for source_idx in source_indices {
+            let kind = ParseErrorKind::UnmatchedLoop;
+            self.recover(parse_error::<()>(self.char_span(source_idx), kind));
         }
     }
 }
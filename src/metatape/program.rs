@@ -1,6 +1,11 @@
 use std::collections::HashMap;
 use std::fmt;
+use std::fmt::Write;
 use std::rc::Rc;
+use std::str::FromStr;
+
+use super::parser::{ParseError, Span};
+use super::runtime::IOMode;
 
 pub type InstructionSeq = Vec<(usize, Instruction)>;
 pub type InstructionBlock = Rc<InstructionSeq>;
@@ -8,9 +13,98 @@ pub type Subroutines = HashMap<String, InstructionBlock>;
 
 #[derive(Debug)]
 pub struct Program {
+    /// Source code of every file in the program, concatenated. Instructions
+    /// store their position in this string.
     pub source: String,
+    pub files: Vec<SourceFile>,
     pub subroutines: Subroutines,
+    /// Position in `source` of the definition of each subroutine.
+    pub definitions: HashMap<String, usize>,
     pub instructions: InstructionBlock,
+    /// Name of the subroutine for each call ID. See `Instruction::Call`.
+    pub call_names: Vec<String>,
+    /// Subroutine for each call ID, or `None` if it is not defined.
+    pub(super) linked_calls: Vec<Option<InstructionBlock>>,
+    /// Warnings produced while parsing the program.
+    pub warnings: Vec<ParseError>,
+}
+
+/// A file that contributed source code to a program.
+#[derive(Debug)]
+pub struct SourceFile {
+    /// Path of the file, or `None` if it did not come from a file.
+    pub path: Option<String>,
+    /// Position of the start of the file in `Program::source`.
+    pub start: usize,
+}
+
+impl Program {
+    /// Parses a program from ASCII source code. See `parse_str()`.
+    pub fn from_source(source: &str) -> Result<Self, Vec<ParseError>> {
+        super::parse_str(source)
+    }
+
+    /// Looks up the subroutine for each call ID. This must be called again
+    /// after changing `subroutines`.
+    pub fn link(&mut self) {
+        self.linked_calls = self
+            .call_names
+            .iter()
+            .map(|name| self.subroutines.get(name).cloned())
+            .collect();
+    }
+
+    /// Returns the subroutine for the given call ID, if it is defined.
+    pub fn linked_call(&self, call_id: usize) -> Option<&InstructionBlock> {
+        self.linked_calls.get(call_id)?.as_ref()
+    }
+
+    /// Returns the file containing the given position in `source`, along
+    /// with the line and column within that file (both starting at 1).
+    pub fn locate(&self, str_idx: usize) -> (&SourceFile, usize, usize) {
+        let (file, file_source) = self.file_source(str_idx);
+        let (line, col) = pest::Position::new(file_source, str_idx - file.start)
+            .expect("Invalid source position")
+            .line_col();
+        (file, line, col)
+    }
+
+    /// Returns a description of a position in `source`, such as
+    /// `examples/cat.mt:3:5`.
+    pub fn describe_position(&self, str_idx: usize) -> String {
+        let (file, line, col) = self.locate(str_idx);
+        match &file.path {
+            Some(path) => format!("{}:{}:{}", path, line, col),
+            None => format!("{}:{}", line, col),
+        }
+    }
+
+    /// Returns the span between two positions in `source`, which must be in
+    /// the same file.
+    pub fn span(&self, start: usize, end: usize) -> Span {
+        let (file, file_source) = self.file_source(start);
+        let pest_span = pest::Span::new(file_source, start - file.start, end - file.start)
+            .expect("Invalid source span");
+        let mut span = Span::from_pest(pest_span);
+        span.path = file.path.clone();
+        span
+    }
+
+    /// Returns the file containing the given position in `source`, along
+    /// with the source code of that file.
+    fn file_source(&self, str_idx: usize) -> (&SourceFile, &str) {
+        let file_idx = self
+            .files
+            .iter()
+            .rposition(|file| file.start <= str_idx)
+            .expect("Program contains no files");
+        let file = &self.files[file_idx];
+        let end = self
+            .files
+            .get(file_idx + 1)
+            .map_or(self.source.len(), |next| next.start);
+        (file, &self.source[file.start..end])
+    }
 }
 
 #[derive(Debug)]
@@ -39,10 +133,12 @@ pub enum Instruction {
 
     Halt,
 
-    // IOMode(IOMode),
-    // Seek(String),
-    Call(String),
-    // Load(String),
+    IOMode(IOMode),
+    Seek(SeekPath),
+    /// Call to a subroutine by name, along with a call ID that is the same
+    /// for every call to that name and is used to find the subroutine
+    /// without a string lookup.
+    Call(String, usize),
     Fork(InstructionBlock),
 }
 
@@ -53,8 +149,110 @@ impl fmt::Display for Instruction {
             // in a block or fork instruction.
             Self::Block(_) => f.write_str("Block(...)"),
             Self::Fork(_) => f.write_str("Fork(...)"),
+            Self::Seek(path) => write!(f, "Seek({})", path),
+            Self::Call(name, _) => write!(f, "Call({:?})", name),
             // Use debug formatting for all the rest.
             _ => f.write_str(&format!("{:?}", self)),
         }
     }
 }
+
+/// Path followed by a seek instruction, such as `<* e >3`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SeekPath(pub Vec<SeekStep>);
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct SeekStep {
+    pub direction: SeekDirection,
+    pub count: SeekCount,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SeekDirection {
+    Left,
+    Right,
+    Enter,
+    Exit,
+}
+
+impl SeekDirection {
+    /// Returns the direction that undoes a move in this direction.
+    pub fn opposite(self) -> Self {
+        match self {
+            Self::Left => Self::Right,
+            Self::Right => Self::Left,
+            Self::Enter => Self::Exit,
+            Self::Exit => Self::Enter,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SeekCount {
+    /// Move a fixed number of times.
+    Times(usize),
+    /// Move at least once, and then keep moving until the current cell is
+    /// null.
+    UntilNull,
+}
+
+impl FromStr for SeekPath {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut steps = vec![];
+        let mut chars = s.chars().filter(|c| !c.is_whitespace()).peekable();
+        while let Some(c) = chars.next() {
+            let direction = match c.to_ascii_lowercase() {
+                '<' => SeekDirection::Left,
+                '>' => SeekDirection::Right,
+                'e' => SeekDirection::Enter,
+                'x' => SeekDirection::Exit,
+                _ => return Err(format!("Invalid seek direction: {:?}", c)),
+            };
+            let count = if chars.peek() == Some(&'*') {
+                chars.next();
+                if direction == SeekDirection::Exit {
+                    return Err("Cannot seek out until null; the parent is never null".to_owned());
+                }
+                SeekCount::UntilNull
+            } else {
+                let mut digits = String::new();
+                while let Some(digit) = chars.peek().filter(|c| c.is_ascii_digit()) {
+                    digits.push(*digit);
+                    chars.next();
+                }
+                SeekCount::Times(if digits.is_empty() {
+                    1
+                } else {
+                    digits
+                        .parse()
+                        .map_err(|_| format!("Invalid seek count: {:?}", digits))?
+                })
+            };
+            steps.push(SeekStep { direction, count });
+        }
+        Ok(Self(steps))
+    }
+}
+
+impl fmt::Display for SeekPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, step) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_char(' ')?;
+            }
+            f.write_char(match step.direction {
+                SeekDirection::Left => '<',
+                SeekDirection::Right => '>',
+                SeekDirection::Enter => 'e',
+                SeekDirection::Exit => 'x',
+            })?;
+            match step.count {
+                SeekCount::Times(1) => (),
+                SeekCount::Times(n) => write!(f, "{}", n)?,
+                SeekCount::UntilNull => f.write_char('*')?,
+            }
+        }
+        Ok(())
+    }
+}
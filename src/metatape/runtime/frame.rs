@@ -0,0 +1,140 @@
+//! Frames on the call stack.
+
+use std::fmt;
+
+use super::Runtime;
+use crate::metatape::parser::Span;
+use crate::metatape::program::{InstructionBlock, Program};
+use crate::metatape::tape::Head;
+
+/// Instruction that started a frame.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FrameKind {
+    Block,
+    /// Call to the subroutine with the given name.
+    Call(String),
+    Fork,
+}
+
+impl fmt::Display for FrameKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameKind::Block => write!(f, "{{...}}"),
+            FrameKind::Call(name) => write!(f, "!{{{}}}", name),
+            FrameKind::Fork => write!(f, "f{{...}}"),
+        }
+    }
+}
+
+/// Subroutine, block, or fork that is currently executing.
+#[derive(Debug, Clone)]
+pub struct Frame {
+    pub kind: FrameKind,
+    /// Position in the program's source of the instruction that started the
+    /// frame.
+    pub source_idx: usize,
+    /// Block containing that instruction, which continues executing when the
+    /// frame returns.
+    pub return_block: InstructionBlock,
+    /// Index of that instruction in `return_block`.
+    pub return_instruction_pointer: usize,
+    /// Head when a fork started, which is restored (keeping the current
+    /// cell's child) when the fork returns.
+    pub(super) fork_head: Option<Head>,
+}
+
+impl Frame {
+    /// Returns the span of the first character of the instruction that
+    /// started the frame.
+    pub fn span(&self, program: &Program) -> Span {
+        program.span(self.source_idx, self.source_idx + 1)
+    }
+}
+
+impl Runtime {
+    /// Returns the subroutines, blocks, and forks that are currently
+    /// executing, outermost first.
+    pub fn get_call_stack(&self) -> &[Frame] {
+        &self.call_stack
+    }
+
+    /// Returns the position of the current instruction followed by one line
+    /// for each frame on the call stack, innermost first. For example:
+    ///
+    /// ```text
+    /// at 99_bottles.mt:117:5
+    /// in !{dec} called from !{dec bottles} at 99_bottles.mt:9:5
+    /// in !{dec bottles} called from the main program at 99_bottles.mt:20:5
+    /// ```
+    pub fn backtrace(&self) -> Vec<String> {
+        let mut ret = vec![];
+        if let Ok((source_idx, _)) = self.fetch_instruction() {
+            ret.push(format!(
+                "at {}",
+                self.program.describe_position(*source_idx)
+            ));
+        }
+        for (idx, frame) in self.call_stack.iter().enumerate().rev() {
+            let verb = match frame.kind {
+                FrameKind::Call(_) => "called",
+                FrameKind::Block | FrameKind::Fork => "entered",
+            };
+            let caller = match idx {
+                0 => "the main program".to_owned(),
+                _ => self.call_stack[idx - 1].kind.to_string(),
+            };
+            let position = self.program.describe_position(frame.source_idx);
+            ret.push(format!(
+                "in {} {} from {} at {}",
+                frame.kind, verb, caller, position
+            ));
+        }
+        ret
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FrameKind;
+    use crate::metatape::{parse_str, Runtime, RuntimeError};
+
+    #[test]
+    fn test_call_stack() {
+        let program = parse_str("{ e f !a }\n@ a {\n  o h\n}").unwrap();
+        let mut runtime = Runtime::new(program).with_output(std::io::sink());
+        let kinds = |runtime: &Runtime| -> Vec<FrameKind> {
+            runtime
+                .get_call_stack()
+                .iter()
+                .map(|frame| frame.kind.clone())
+                .collect()
+        };
+        let result = std::iter::repeat_with(|| runtime.step()).find(Result::is_err);
+        assert!(matches!(result, Some(Err(RuntimeError::Halt))));
+        assert_eq!(
+            kinds(&runtime),
+            [
+                FrameKind::Block,
+                FrameKind::Fork,
+                FrameKind::Call("a".to_owned())
+            ],
+        );
+        assert_eq!(
+            runtime.backtrace(),
+            [
+                "at 3:5",
+                "in !{a} called from f{...} at 1:7",
+                "in f{...} entered from {...} at 1:5",
+                "in {...} entered from the main program at 1:1",
+            ],
+        );
+        let frame = &runtime.get_call_stack()[2];
+        assert_eq!(frame.span(runtime.get_program()).start_line_col, (1, 7));
+        assert_eq!(frame.return_instruction_pointer, 0);
+
+        // `h` is the last instruction in every frame.
+        runtime.unhalt().unwrap();
+        assert!(runtime.get_call_stack().is_empty());
+        assert!(matches!(runtime.step(), Err(RuntimeError::EndOfProgram)));
+    }
+}
@@ -1,59 +1,380 @@
-use io::{Read, Write};
+use io::{BufWriter, Read, Write};
+use std::cell::RefCell;
+use std::fmt;
 use std::io;
+use std::mem;
+use std::rc::Rc;
+use std::str::FromStr;
 
+/// Encoding used to convert between bits and the bytes of stdin/stdout.
+///
+/// Input and output are both handled one "unit" at a time (e.g. a byte). A new
+/// mode only takes effect at the start of the next unit.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum IOMode {
+    /// Each byte is eight bits, most significant bit first.
+    #[default]
+    Msb,
+    /// Each byte is eight bits, least significant bit first.
+    Lsb,
+    /// Each bit is an ASCII `'0'` or `'1'` character. On input, whitespace is
+    /// skipped and any character other than `'1'` is a `0` bit.
+    Ascii,
+    /// Each Unicode code point is 32 bits, most significant bit first, and is
+    /// encoded using UTF-8. Invalid UTF-8 and invalid code points become
+    /// U+FFFD.
+    Utf8,
+}
+
+impl IOMode {
+    fn unit_bits(self) -> u32 {
+        match self {
+            Self::Msb | Self::Lsb => 8,
+            Self::Ascii => 1,
+            Self::Utf8 => 32,
+        }
+    }
+}
+
+impl FromStr for IOMode {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "msb" => Ok(Self::Msb),
+            "lsb" => Ok(Self::Lsb),
+            "ascii" => Ok(Self::Ascii),
+            "utf8" => Ok(Self::Utf8),
+            _ => Err(format!("Unknown I/O mode: {:?}", s)),
+        }
+    }
+}
+
+impl fmt::Display for IOMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Msb => "msb",
+            Self::Lsb => "lsb",
+            Self::Ascii => "ascii",
+            Self::Utf8 => "utf8",
+        })
+    }
+}
+
+/// Behavior of the input instruction once the input has been exhausted.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum EofPolicy {
+    /// Read `0` bits forever.
+    #[default]
+    Zero,
+    /// End the program.
+    Halt,
+    /// Raise `RuntimeError::EndOfInput`.
+    Error,
+    /// Precede each unit of input with a `1` bit, and read a single `0` bit
+    /// (instead of a whole unit) at the end of the input.
+    Flag,
+}
+
+impl FromStr for EofPolicy {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "zero" => Ok(Self::Zero),
+            "halt" => Ok(Self::Halt),
+            "error" => Ok(Self::Error),
+            "flag" => Ok(Self::Flag),
+            _ => Err(format!("Unknown EOF policy: {:?}", s)),
+        }
+    }
+}
+
+/// Behavior at the end of the program if the last unit of output is
+/// incomplete.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum PartialOutputPolicy {
+    /// Complete the unit with `0` bits and write it.
+    Pad,
+    /// Leave the bits pending, so that they can be reported using
+    /// `Runtime::pending_output_bits()`.
+    #[default]
+    Warn,
+    /// Raise `RuntimeError::PartialOutput`.
+    Error,
+}
+
+impl FromStr for PartialOutputPolicy {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "pad" => Ok(Self::Pad),
+            "warn" => Ok(Self::Warn),
+            "error" => Ok(Self::Error),
+            _ => Err(format!("Unknown partial output policy: {:?}", s)),
+        }
+    }
+}
+
+/// Buffer of input bits, read from stdin by default.
 pub struct StdInBitBuffer {
-    byte: u8,
-    bit_idx: u8,
-    pub byte_reader: Box<dyn Fn() -> u8>,
+    /// Current unit of input, most significant bit first.
+    unit: u64,
+    /// Number of bits of `unit` that have not been read yet.
+    bits_left: u32,
+    pub mode: IOMode,
+    pub eof_policy: EofPolicy,
+    pub reader: Box<dyn Read>,
 }
 
 impl StdInBitBuffer {
     pub fn new() -> Self {
         Self {
-            byte: 0,
-            bit_idx: 0,
-            // If for whatever reason we can't read the byte, use 0.
-            byte_reader: Box::new(|| io::stdin().bytes().next().unwrap_or(Ok(0)).unwrap_or(0)),
+            unit: 0,
+            bits_left: 0,
+            mode: IOMode::default(),
+            eof_policy: EofPolicy::default(),
+            reader: Box::new(io::stdin()),
+        }
+    }
+    /// Reads a byte, returning `None` if there are no more bytes or if for
+    /// whatever reason we can't read the byte.
+    fn read_byte(&mut self) -> Option<u8> {
+        let mut byte = [0];
+        self.reader.read_exact(&mut byte).ok().map(|_| byte[0])
+    }
+    /// Reads a bit, returning `None` if the input has been exhausted and the
+    /// EOF policy doesn't say what to do about it.
+    pub fn read_bit(&mut self) -> Option<bool> {
+        if self.bits_left == 0 {
+            let unit_bits = self.mode.unit_bits();
+            match (self.read_unit(), self.eof_policy) {
+                (Some(unit), EofPolicy::Flag) => {
+                    self.unit = (1 << unit_bits) | unit as u64;
+                    self.bits_left = unit_bits + 1;
+                }
+                (Some(unit), _) => {
+                    self.unit = unit as u64;
+                    self.bits_left = unit_bits;
+                }
+                (None, EofPolicy::Zero) => {
+                    self.unit = 0;
+                    self.bits_left = unit_bits;
+                }
+                (None, EofPolicy::Flag) => {
+                    self.unit = 0;
+                    self.bits_left = 1;
+                }
+                (None, EofPolicy::Halt) | (None, EofPolicy::Error) => return None,
+            }
         }
+        self.bits_left -= 1;
+        Some(self.unit & (1 << self.bits_left) != 0)
+    }
+    /// Returns the bits of the current unit that have not been read yet.
+    pub fn pending_bits(&self) -> Vec<bool> {
+        (0..self.bits_left)
+            .rev()
+            .map(|i| self.unit & (1 << i) != 0)
+            .collect()
     }
-    pub fn read_bit(&mut self) -> bool {
-        if self.bit_idx == 0 {
-            self.bit_idx = 8;
-            self.byte = (self.byte_reader)();
+    /// Replaces the bits of the current unit that have not been read yet.
+    /// There can be at most 64 bits.
+    pub fn set_pending_bits(&mut self, bits: &[bool]) {
+        assert!(bits.len() <= 64, "Too many pending input bits");
+        self.unit = bits.iter().fold(0, |unit, &bit| (unit << 1) | bit as u64);
+        self.bits_left = bits.len() as u32;
+    }
+    fn read_unit(&mut self) -> Option<u32> {
+        match self.mode {
+            IOMode::Msb => self.read_byte().map(|byte| byte as u32),
+            IOMode::Lsb => self.read_byte().map(|byte| byte.reverse_bits() as u32),
+            IOMode::Ascii => loop {
+                match self.read_byte()? {
+                    byte if byte.is_ascii_whitespace() => continue,
+                    b'1' => break Some(1),
+                    _ => break Some(0),
+                }
+            },
+            IOMode::Utf8 => self.read_char().map(|c| c as u32),
         }
-        self.bit_idx -= 1;
-        self.byte & (1 << self.bit_idx) != 0
+    }
+    fn read_char(&mut self) -> Option<char> {
+        let first = self.read_byte()?;
+        let len = match first {
+            0x00..=0x7f => return Some(first as char),
+            0xc0..=0xdf => 2,
+            0xe0..=0xef => 3,
+            0xf0..=0xf7 => 4,
+            _ => return Some(char::REPLACEMENT_CHARACTER),
+        };
+        let mut bytes = vec![first];
+        for _ in 1..len {
+            // A truncated character is still a character.
+            bytes.extend(self.read_byte());
+        }
+        Some(
+            std::str::from_utf8(&bytes)
+                .ok()
+                .and_then(|s| s.chars().next())
+                .unwrap_or(char::REPLACEMENT_CHARACTER),
+        )
     }
 }
 
+/// Buffer of output bits, written to stdout by default.
 pub struct StdOutBitBuffer {
-    byte: u8,
-    bit_idx: u8,
-    pub byte_writer: Box<dyn Fn(u8)>,
+    /// Bits of the current unit that have been written so far.
+    unit: u32,
+    /// Number of bits in `unit`.
+    bit_count: u32,
+    /// Mode of the current unit.
+    unit_mode: IOMode,
+    pub mode: IOMode,
+    /// Whether to wait for a newline (or an explicit flush) before writing
+    /// output, instead of flushing after every unit.
+    pub buffered: bool,
+    writer: BufWriter<Box<dyn Write>>,
+    /// Number of bits written so far.
+    bits_written: u64,
+    /// Value of `bits_written` after the last unit that was passed to the
+    /// writer. Units are not written again after rewinding.
+    written_until: u64,
+}
+
+/// State of an output buffer, which can be restored with
+/// `StdOutBitBuffer::rewind()`.
+#[derive(Debug, Clone)]
+pub struct OutputPosition {
+    unit: u32,
+    bit_count: u32,
+    unit_mode: IOMode,
+    mode: IOMode,
+    bits_written: u64,
 }
 
 impl StdOutBitBuffer {
     pub fn new() -> Self {
         Self {
-            byte: 0,
-            bit_idx: 8,
-            byte_writer: Box::new(|byte| {
-                // We don't care whether the write actually succeeds.
-                let _ = io::stdout().write(&[byte]);
-                let _ = io::stdout().flush();
-            }),
+            unit: 0,
+            bit_count: 0,
+            unit_mode: IOMode::default(),
+            mode: IOMode::default(),
+            buffered: true,
+            writer: BufWriter::new(Box::new(io::stdout())),
+            bits_written: 0,
+            written_until: 0,
+        }
+    }
+    pub fn position(&self) -> OutputPosition {
+        OutputPosition {
+            unit: self.unit,
+            bit_count: self.bit_count,
+            unit_mode: self.unit_mode,
+            mode: self.mode,
+            bits_written: self.bits_written,
+        }
+    }
+    /// Goes back to an earlier position. Units that have already been
+    /// written are skipped when they are written again.
+    pub fn rewind(&mut self, position: &OutputPosition) {
+        self.unit = position.unit;
+        self.bit_count = position.bit_count;
+        self.unit_mode = position.unit_mode;
+        self.mode = position.mode;
+        self.bits_written = position.bits_written;
+    }
+    /// Flushes any buffered output and then replaces the writer, returning
+    /// the old one.
+    pub fn set_writer(&mut self, writer: Box<dyn Write>) -> Box<dyn Write> {
+        self.flush();
+        mem::replace(&mut self.writer, BufWriter::new(writer))
+            .into_parts()
+            .0
+    }
+    pub fn flush(&mut self) {
+        // We don't care whether the write actually succeeds.
+        let _ = self.writer.flush();
+    }
+    /// Returns the bits of the current unit that have been written so far.
+    pub fn pending_bits(&self) -> Vec<bool> {
+        (0..self.bit_count)
+            .rev()
+            .map(|i| self.unit & (1 << i) != 0)
+            .collect()
+    }
+    /// Returns the mode of the current unit.
+    pub fn unit_mode(&self) -> IOMode {
+        self.unit_mode
+    }
+    /// Replaces the current unit with one that has been partially written
+    /// already (e.g. before the state of the runtime was saved).
+    pub fn set_pending_bits(&mut self, unit_mode: IOMode, bits: &[bool]) -> Result<(), String> {
+        if bits.len() >= unit_mode.unit_bits() as usize {
+            return Err(format!(
+                "{} pending output bits is too many for {} mode",
+                bits.len(),
+                unit_mode,
+            ));
+        }
+        self.unit = bits.iter().fold(0, |unit, &bit| (unit << 1) | bit as u32);
+        self.bit_count = bits.len() as u32;
+        self.unit_mode = unit_mode;
+        self.written_until = self.bits_written;
+        Ok(())
+    }
+    /// Completes the current unit with `0` bits, if it has been started.
+    pub fn pad(&mut self) {
+        while self.bit_count != 0 {
+            self.write_bit(false);
+        }
+    }
+    fn write_bytes(&mut self, bytes: &[u8]) {
+        // We don't care whether the write actually succeeds.
+        let _ = self.writer.write_all(bytes);
+        if !self.buffered || bytes.contains(&b'\n') {
+            self.flush();
         }
     }
     pub fn write_bit(&mut self, bit: bool) {
-        self.bit_idx -= 1;
-        if bit {
-            // Set the bit.
-            self.byte |= 1 << self.bit_idx;
+        if self.bit_count == 0 {
+            self.unit_mode = self.mode;
         }
-        if self.bit_idx == 0 {
-            (self.byte_writer)(self.byte);
-            self.bit_idx = 8;
-            self.byte = 0;
+        self.unit = (self.unit << 1) | bit as u32;
+        self.bit_count += 1;
+        self.bits_written += 1;
+        if self.bit_count == self.unit_mode.unit_bits() {
+            if self.bits_written > self.written_until {
+                self.write_unit();
+                self.written_until = self.bits_written;
+            }
+            self.unit = 0;
+            self.bit_count = 0;
         }
     }
+    fn write_unit(&mut self) {
+        match self.unit_mode {
+            IOMode::Msb => self.write_bytes(&[self.unit as u8]),
+            IOMode::Lsb => self.write_bytes(&[(self.unit as u8).reverse_bits()]),
+            IOMode::Ascii => self.write_bytes(if self.unit == 1 { b"1" } else { b"0" }),
+            IOMode::Utf8 => {
+                let c = std::char::from_u32(self.unit).unwrap_or(char::REPLACEMENT_CHARACTER);
+                let mut buf = [0; 4];
+                self.write_bytes(c.encode_utf8(&mut buf).as_bytes());
+            }
+        }
+    }
+}
+
+/// Byte buffer that can be shared between a `Runtime` and its owner, for
+/// capturing output.
+#[derive(Clone, Default)]
+pub struct SharedBuffer(pub Rc<RefCell<Vec<u8>>>);
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
 }
@@ -1,12 +1,22 @@
 #![allow(dead_code)]
 
+mod frame;
 mod io;
+mod random;
+mod save;
+mod snapshot;
 
-use rand::thread_rng;
-use rand::RngCore;
+pub use frame::{Frame, FrameKind};
+pub use io::{EofPolicy, IOMode, PartialOutputPolicy, SharedBuffer};
+pub use random::{BitSequence, RandomSource};
+pub use snapshot::Snapshot;
+
+use rand::{thread_rng, SeedableRng};
+use rand_chacha::ChaCha20Rng;
+use std::io::{Cursor, Read, Write};
 use std::mem;
 
-use super::program::{Instruction, InstructionBlock, Program};
+use super::program::{Instruction, InstructionBlock, Program, SeekCount, SeekDirection, SeekPath};
 use super::tape::Head;
 
 pub struct Runtime {
@@ -14,15 +24,26 @@ pub struct Runtime {
     head: Head,
     executing_block: InstructionBlock,
     instruction_pointer: usize,
-    call_stack: Vec<Box<dyn FnOnce(&mut Runtime)>>,
-    /// Buffer of input bits. The lowest bit is at index 0, and the highest bit is at index 7.
+    call_stack: Vec<Frame>,
+    /// Buffer of input bits, decoded according to the current I/O mode.
     input_buffer: io::StdInBitBuffer,
-    /// Buffor of output bits. The highest bit is at index 0, and the lowest bit is at index 7.
+    /// Buffer of output bits, encoded according to the current I/O mode.
     output_buffer: io::StdOutBitBuffer,
+    partial_output_policy: PartialOutputPolicy,
+    /// Source of the bits generated by `?`.
+    random_source: Box<dyn RandomSource>,
+    /// Input and random bits that have been read, for replaying them after
+    /// restoring a snapshot.
+    input_log: snapshot::BitLog,
+    random_log: snapshot::BitLog,
+    /// Whether `?` has generated any bits.
+    used_random_bits: bool,
 }
 
 impl Runtime {
-    pub(super) fn new(program: Program) -> Self {
+    pub fn new(mut program: Program) -> Self {
+        // Subroutines may have changed since the program was parsed.
+        program.link();
         let executing_block = program.instructions.clone();
         Self {
             program,
@@ -32,9 +53,59 @@ impl Runtime {
             call_stack: vec![],
             input_buffer: io::StdInBitBuffer::new(),
             output_buffer: io::StdOutBitBuffer::new(),
+            partial_output_policy: PartialOutputPolicy::default(),
+            random_source: Box::new(thread_rng()),
+            input_log: snapshot::BitLog::default(),
+            random_log: snapshot::BitLog::default(),
+            used_random_bits: false,
         }
     }
 
+    pub fn with_io_mode(mut self, mode: IOMode) -> Self {
+        self.set_io_mode(mode);
+        self
+    }
+
+    pub fn with_eof_policy(mut self, eof_policy: EofPolicy) -> Self {
+        self.set_eof_policy(eof_policy);
+        self
+    }
+
+    pub fn with_input(mut self, input: impl Read + 'static) -> Self {
+        self.set_input(input);
+        self
+    }
+
+    pub fn with_input_bytes(mut self, input: impl Into<Vec<u8>>) -> Self {
+        self.set_input_bytes(input);
+        self
+    }
+
+    pub fn with_output(mut self, output: impl Write + 'static) -> Self {
+        self.set_output(output);
+        self
+    }
+
+    pub fn with_partial_output_policy(mut self, policy: PartialOutputPolicy) -> Self {
+        self.set_partial_output_policy(policy);
+        self
+    }
+
+    pub fn with_random_source(mut self, random_source: impl RandomSource + 'static) -> Self {
+        self.set_random_source(random_source);
+        self
+    }
+
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.set_seed(seed);
+        self
+    }
+
+    pub fn with_buffered_output(mut self, buffered: bool) -> Self {
+        self.set_buffered_output(buffered);
+        self
+    }
+
     pub fn get_program(&self) -> &Program {
         &self.program
     }
@@ -51,19 +122,42 @@ impl Runtime {
         self.instruction_pointer
     }
 
+    /// Returns the number of subroutines, blocks, and forks that are
+    /// currently executing.
+    pub fn call_depth(&self) -> usize {
+        self.call_stack.len()
+    }
+
+    /// Executes a single instruction. Buffered output is flushed whenever this
+    /// returns an error, including when the program halts or ends.
     pub fn step(&mut self) -> Result<ExecDebugInfo, RuntimeError> {
+        let mut ret = self.exec_instruction();
+        if let Err(RuntimeError::EndOfProgram) = ret {
+            let pending_bits = self.output_buffer.pending_bits().len();
+            if pending_bits != 0 {
+                match self.partial_output_policy {
+                    PartialOutputPolicy::Pad => self.output_buffer.pad(),
+                    PartialOutputPolicy::Warn => (),
+                    PartialOutputPolicy::Error => {
+                        ret = Err(RuntimeError::PartialOutput(pending_bits))
+                    }
+                }
+            }
+        }
+        if ret.is_err() {
+            self.flush_output();
+        }
+        ret
+    }
+
+    fn exec_instruction(&mut self) -> Result<ExecDebugInfo, RuntimeError> {
         // Fetch the current block.
-        let (_current_instruction_str_idx, current_instruction) = self.fetch_instruction()?;
+        let (source_idx, current_instruction) = self.fetch_instruction()?;
+        let source_idx = *source_idx;
         let mut exec_debug_info = ExecDebugInfo { bit: None };
-        struct Call {
-            new_executing_block: Option<InstructionBlock>,
-            head_restore_function: Option<Box<dyn FnOnce(&Head, &Head) -> Head>>,
-        }
+        // Subroutine, block, or fork to start executing.
+        let mut new_frame: Option<(FrameKind, InstructionBlock)> = None;
 
-        let mut call: Call = Call {
-            new_executing_block: None,
-            head_restore_function: None,
-        };
         // Fetch the current instruction.
         match current_instruction {
             Instruction::Nop | Instruction::EndIf | Instruction::Loop => (),
@@ -74,6 +168,7 @@ impl Runtime {
             Instruction::Exit => self.head = self.head.exit(),
 
             Instruction::Null => self.head = self.head.null_child(),
+            Instruction::Seek(path) => self.head = seek(&self.head, path, |_| ()),
 
             Instruction::If(destination) => {
                 if self.head.has_child() {
@@ -88,41 +183,43 @@ impl Runtime {
             }
 
             Instruction::Block(instruction_block) => {
-                call.new_executing_block = Some(instruction_block.clone());
+                new_frame = Some((FrameKind::Block, instruction_block.clone()));
             }
 
-            Instruction::Call(subroutine_name) => {
-                call.new_executing_block = Some(
-                    self.program
-                        .subroutines
-                        .get(subroutine_name)
-                        .ok_or_else(|| {
-                            RuntimeError::SubroutineNotFound(subroutine_name.to_string())
-                        })?
-                        .clone(),
-                );
+            Instruction::Call(subroutine_name, call_id) => {
+                let subroutine = self
+                    .program
+                    .linked_call(*call_id)
+                    .ok_or_else(|| RuntimeError::SubroutineNotFound(subroutine_name.to_string()))?
+                    .clone();
+                new_frame = Some((FrameKind::Call(subroutine_name.clone()), subroutine));
             }
             Instruction::Fork(instruction_block) => {
-                call.new_executing_block = Some(instruction_block.clone());
-                call.head_restore_function = Some(Box::new(|old_head, new_head| {
-                    old_head.copy_child_from(&new_head)
-                }));
+                new_frame = Some((FrameKind::Fork, instruction_block.clone()));
             }
 
-            Instruction::Random => {
-                if thread_rng().next_u32() % 2 == 1 {
-                    exec_debug_info.bit = Some(true);
-                } else {
+            Instruction::Random => match self.next_random_bit() {
+                Some(true) => exec_debug_info.bit = Some(true),
+                Some(false) => {
                     exec_debug_info.bit = Some(false);
                     self.head = self.head.null_child();
                 }
-            }
+                None => return Err(RuntimeError::RandomExhausted),
+            },
             Instruction::Input => {
-                if self.input_buffer.read_bit() {
-                    exec_debug_info.bit = Some(true);
-                } else {
-                    exec_debug_info.bit = Some(false);
-                    self.head = self.head.null_child();
+                // Make sure that any prompt is visible before waiting for
+                // input.
+                self.flush_output();
+                match self.read_input_bit() {
+                    Some(true) => exec_debug_info.bit = Some(true),
+                    Some(false) => {
+                        exec_debug_info.bit = Some(false);
+                        self.head = self.head.null_child();
+                    }
+                    None if self.input_buffer.eof_policy == EofPolicy::Halt => {
+                        return Err(RuntimeError::EndOfProgram);
+                    }
+                    None => return Err(RuntimeError::EndOfInput),
                 }
             }
             Instruction::Output => {
@@ -133,53 +230,66 @@ impl Runtime {
             Instruction::Halt => {
                 return Err(RuntimeError::Halt);
             }
+
+            Instruction::IOMode(mode) => self.set_io_mode(*mode),
         }
-        if let Call {
-            new_executing_block: None,
-            head_restore_function: None,
-        } = call
-        {
-            // Ignore the returned Result because we don't care if we are
-            // currently at the last instruction; we'll just raise an error
-            // next time this function is called.
-            let _ = self.go_to_next_instruction();
-        } else {
-            let exec_block_fn: Box<dyn FnOnce(&mut Runtime)> = match call.new_executing_block {
-                None => Box::new(|_| ()),
-                Some(new_executing_block) => {
-                    let old_index = mem::replace(&mut self.instruction_pointer, 0);
-                    let old_executing_block =
-                        mem::replace(&mut self.executing_block, new_executing_block);
-                    Box::new(move |runtime: &mut Runtime| {
-                        runtime.instruction_pointer = old_index;
-                        runtime.executing_block = old_executing_block;
-                    })
-                }
-            };
-            let head_fn: Box<dyn FnOnce(&mut Runtime)> = match call.head_restore_function {
-                None => Box::new(move |_| ()),
-                Some(restore_head) => {
-                    let old_head = self.head.clone();
-                    Box::new(move |runtime: &mut Runtime| {
-                        runtime.head = restore_head(&old_head, &runtime.head)
-                    })
+        match new_frame {
+            None => {
+                // Ignore the returned Result because we don't care if we are
+                // currently at the last instruction; we'll just raise an error
+                // next time this function is called.
+                let _ = self.go_to_next_instruction();
+            }
+            Some((kind, new_executing_block)) => {
+                // Forks restore the head when they return.
+                let fork_head = match kind {
+                    FrameKind::Fork => Some(self.head.clone()),
+                    _ => None,
+                };
+                self.call_stack.push(Frame {
+                    kind,
+                    source_idx,
+                    return_block: mem::replace(&mut self.executing_block, new_executing_block),
+                    return_instruction_pointer: mem::replace(&mut self.instruction_pointer, 0),
+                    fork_head,
+                });
+                while let Err(RuntimeError::InstructionPointerOutOfBounds) =
+                    self.fetch_instruction()
+                {
+                    self.go_to_next_instruction()?;
                 }
-            };
-            self.call_stack.push(Box::new(|runtime: &mut Runtime| {
-                exec_block_fn(runtime);
-                head_fn(runtime);
-            }));
-            while let Err(RuntimeError::InstructionPointerOutOfBounds) = self.fetch_instruction() {
-                self.go_to_next_instruction()?;
             }
         }
         Ok(exec_debug_info)
     }
 
+    fn next_random_bit(&mut self) -> Option<bool> {
+        let random_source = &mut self.random_source;
+        let used_random_bits = &mut self.used_random_bits;
+        self.random_log.next_bit(|| {
+            *used_random_bits = true;
+            random_source.next_bit()
+        })
+    }
+
+    fn read_input_bit(&mut self) -> Option<bool> {
+        let input_buffer = &mut self.input_buffer;
+        self.input_log.next_bit(|| input_buffer.read_bit())
+    }
+
+    /// Returns the instruction that will be executed next. Past the end of
+    /// the program, this returns `RuntimeError::EndOfProgram` (the same
+    /// error that `step()` returns when the program ends);
+    /// `RuntimeError::InstructionPointerOutOfBounds` is only returned past the
+    /// end of a subroutine, block, or fork.
     pub fn fetch_instruction(&self) -> Result<&(usize, Instruction), RuntimeError> {
         self.executing_block
             .get(self.instruction_pointer)
-            .ok_or(RuntimeError::InstructionPointerOutOfBounds)
+            .ok_or(if self.call_stack.is_empty() {
+                RuntimeError::EndOfProgram
+            } else {
+                RuntimeError::InstructionPointerOutOfBounds
+            })
     }
 
     fn go_to_next_instruction(&mut self) -> Result<(), RuntimeError> {
@@ -189,30 +299,159 @@ impl Runtime {
             // If we have reached the end of this block ...
             if self.instruction_pointer >= self.executing_block.len() {
                 // ... then pop one off the stack.
-                self.call_stack.pop().ok_or(RuntimeError::EndOfProgram)?(self);
+                let frame = self.call_stack.pop().ok_or(RuntimeError::EndOfProgram)?;
+                self.instruction_pointer = frame.return_instruction_pointer;
+                self.executing_block = frame.return_block;
+                if let Some(old_head) = frame.fork_head {
+                    self.head = old_head.copy_child_from(&self.head);
+                }
             } else {
                 return Ok(());
             }
         }
     }
 
-    pub fn set_input_fn(&mut self, input_function: Box<dyn Fn() -> u8>) {
-        self.input_buffer.byte_reader = input_function;
+    pub fn set_eof_policy(&mut self, eof_policy: EofPolicy) {
+        self.input_buffer.eof_policy = eof_policy;
     }
 
-    pub fn set_output_fn(&mut self, output_function: Box<dyn Fn(u8)>) {
-        self.output_buffer.byte_writer = output_function;
+    pub fn set_input(&mut self, input: impl Read + 'static) {
+        self.input_buffer.reader = Box::new(input);
     }
 
+    pub fn set_input_bytes(&mut self, input: impl Into<Vec<u8>>) {
+        self.set_input(Cursor::new(input.into()));
+    }
+
+    pub fn set_output(&mut self, output: impl Write + 'static) {
+        self.output_buffer.set_writer(Box::new(output));
+    }
+
+    /// Sets whether output is buffered until a newline, the next input, or
+    /// the end of the program. Output is buffered by default.
+    pub fn set_buffered_output(&mut self, buffered: bool) {
+        self.output_buffer.buffered = buffered;
+        if !buffered {
+            self.flush_output();
+        }
+    }
+
+    pub fn set_partial_output_policy(&mut self, policy: PartialOutputPolicy) {
+        self.partial_output_policy = policy;
+    }
+
+    pub fn set_random_source(&mut self, random_source: impl RandomSource + 'static) {
+        self.random_source = Box::new(random_source);
+    }
+
+    /// Makes `?` generate the same sequence of bits every time for a given
+    /// seed. This uses ChaCha20 rather than `rand`'s `StdRng`, whose
+    /// algorithm may change between versions, so that a seed generates the
+    /// same bits with every build.
+    pub fn set_seed(&mut self, seed: u64) {
+        self.set_random_source(ChaCha20Rng::seed_from_u64(seed));
+    }
+
+    /// Returns whether `?` has generated any bits, so the random seed only
+    /// matters for reproducing the run if this is true.
+    pub fn used_random_bits(&self) -> bool {
+        self.used_random_bits
+    }
+
+    /// Returns the bits of the output unit that has been started but not
+    /// completed. If this is not empty at the end of the program, then the
+    /// program did not output a whole number of units.
+    pub fn pending_output_bits(&self) -> Vec<bool> {
+        self.output_buffer.pending_bits()
+    }
+
+    pub fn flush_output(&mut self) {
+        self.output_buffer.flush();
+    }
+
+    /// Runs the program until it ends, using the given bytes as input, and
+    /// returns everything it outputs. Halts are ignored. Any output written
+    /// before calling this method is not included. The runtime's previous
+    /// input and output are restored afterwards, even if there is an error.
+    pub fn run_to_completion(
+        &mut self,
+        input: impl Into<Vec<u8>>,
+    ) -> Result<Vec<u8>, RuntimeError> {
+        let output = io::SharedBuffer::default();
+        let old_input = mem::replace(
+            &mut self.input_buffer.reader,
+            Box::new(Cursor::new(input.into())),
+        );
+        let old_output = self.output_buffer.set_writer(Box::new(output.clone()));
+        let result = loop {
+            match self.step() {
+                Ok(_) => (),
+                Err(RuntimeError::Halt) => {
+                    if let Err(err) = self.unhalt() {
+                        break Err(err);
+                    }
+                }
+                Err(RuntimeError::EndOfProgram) => break Ok(()),
+                Err(err) => break Err(err),
+            }
+        };
+        self.input_buffer.reader = old_input;
+        self.output_buffer.set_writer(old_output);
+        result.map(|()| output.0.take())
+    }
+
+    pub fn set_io_mode(&mut self, mode: IOMode) {
+        self.input_buffer.mode = mode;
+        self.output_buffer.mode = mode;
+    }
+
+    /// Moves past the `h` that halted the program. If it was the last
+    /// instruction, this succeeds and the next call to `step()` returns
+    /// `RuntimeError::EndOfProgram`, so a program that ends with `h` ends the
+    /// same way as any other program.
     pub fn unhalt(&mut self) -> Result<(), RuntimeError> {
         if let Ok((_, Instruction::Halt)) = self.fetch_instruction() {
-            self.go_to_next_instruction()
+            match self.go_to_next_instruction() {
+                Err(RuntimeError::EndOfProgram) => Ok(()),
+                result => result,
+            }
         } else {
             Err(RuntimeError::NotHalted)
         }
     }
 }
 
+/// Moves the head along a path, calling `on_move` for each movement.
+pub(crate) fn seek(head: &Head, path: &SeekPath, mut on_move: impl FnMut(SeekDirection)) -> Head {
+    let mut head = head.clone();
+    for step in &path.0 {
+        let direction_fn = match step.direction {
+            SeekDirection::Left => Head::move_left,
+            SeekDirection::Right => Head::move_right,
+            SeekDirection::Enter => Head::enter,
+            SeekDirection::Exit => Head::exit,
+        };
+        let mut move_head = |head: &Head| {
+            on_move(step.direction);
+            direction_fn(head)
+        };
+        match step.count {
+            SeekCount::Times(n) => {
+                for _ in 0..n {
+                    head = move_head(&head);
+                }
+            }
+            SeekCount::UntilNull => {
+                head = move_head(&head);
+                while head.has_child() {
+                    head = move_head(&head);
+                }
+            }
+        }
+    }
+    head
+}
+
 pub struct ExecDebugInfo {
     pub bit: Option<bool>,
 }
@@ -222,6 +461,40 @@ pub enum RuntimeError {
     EndOfProgram,
     InstructionPointerOutOfBounds,
     SubroutineNotFound(String),
+    EndOfInput,
+    /// The program ended partway through a unit of output, after outputting
+    /// the given number of bits.
+    PartialOutput(usize),
+    /// The random source ran out of bits.
+    RandomExhausted,
     Halt,
     NotHalted,
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::metatape::{parse_str, PartialOutputPolicy, Runtime, RuntimeError};
+
+    fn run(source: &str) -> Vec<u8> {
+        let mut runtime = Runtime::new(parse_str(source).unwrap());
+        runtime.run_to_completion(vec![]).unwrap()
+    }
+
+    #[test]
+    fn test_run_to_completion() {
+        assert_eq!(run("ex o o o o o o o o"), [0xff]);
+        // Halts are ignored, even at the end of the program.
+        assert_eq!(run("ex o o o o h o o o o"), [0xff]);
+        assert_eq!(run("ex o o o o o o o o h"), [0xff]);
+    }
+
+    #[test]
+    fn test_partial_output_after_halt() {
+        let runtime =
+            |policy| Runtime::new(parse_str("ex o h").unwrap()).with_partial_output_policy(policy);
+        let result = runtime(PartialOutputPolicy::Error).run_to_completion(vec![]);
+        assert!(matches!(result, Err(RuntimeError::PartialOutput(1))));
+        let result = runtime(PartialOutputPolicy::Pad).run_to_completion(vec![]);
+        assert_eq!(result.unwrap(), [0x80]);
+    }
+}
@@ -0,0 +1,81 @@
+use rand::rngs::{StdRng, ThreadRng};
+use rand::RngCore;
+use rand_chacha::ChaCha20Rng;
+use std::str::FromStr;
+
+/// Source of the bits generated by `?`.
+pub trait RandomSource {
+    /// Returns the next random bit, or `None` if there are no more bits.
+    fn next_bit(&mut self) -> Option<bool>;
+}
+
+impl RandomSource for ThreadRng {
+    fn next_bit(&mut self) -> Option<bool> {
+        Some(self.next_u32() % 2 == 1)
+    }
+}
+
+impl RandomSource for StdRng {
+    fn next_bit(&mut self) -> Option<bool> {
+        Some(self.next_u32() % 2 == 1)
+    }
+}
+
+impl RandomSource for ChaCha20Rng {
+    fn next_bit(&mut self) -> Option<bool> {
+        Some(self.next_u32() % 2 == 1)
+    }
+}
+
+/// Fixed sequence of "random" bits, for replaying or testing a program.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct BitSequence {
+    bits: Vec<bool>,
+    idx: usize,
+}
+
+impl From<Vec<bool>> for BitSequence {
+    fn from(bits: Vec<bool>) -> Self {
+        Self { bits, idx: 0 }
+    }
+}
+
+impl FromStr for BitSequence {
+    type Err = String;
+    /// Parses a string of `0`s and `1`s, ignoring whitespace.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.chars()
+            .filter(|c| !c.is_whitespace())
+            .map(|c| match c {
+                '0' => Ok(false),
+                '1' => Ok(true),
+                _ => Err(format!("Invalid bit in random bit sequence: {:?}", c)),
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .map(Self::from)
+    }
+}
+
+impl RandomSource for BitSequence {
+    fn next_bit(&mut self) -> Option<bool> {
+        let ret = self.bits.get(self.idx).copied();
+        if ret.is_some() {
+            self.idx += 1;
+        }
+        ret
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::metatape::{parse_str, Runtime};
+
+    #[test]
+    fn test_seed() {
+        // Seeds must keep generating the same bits, or old runs can't be
+        // reproduced.
+        let program = parse_str("ex?o ex?o ex?o ex?o ex?o ex?o ex?o ex?o").unwrap();
+        let mut runtime = Runtime::new(program).with_seed(5);
+        assert_eq!(runtime.run_to_completion(vec![]).unwrap(), b"H");
+    }
+}
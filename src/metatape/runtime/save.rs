@@ -0,0 +1,399 @@
+//! Saving the state of a runtime to text, so that it can be resumed later.
+//!
+//! The state is saved one item per line, starting with `metatape state 1`.
+//! Tapes and cells are written as described in `tape::serialize`, so the head
+//! (and the head saved by each fork on the call stack) refer to them by
+//! number. Blocks are numbered in the order returned by `program_blocks()`.
+//!
+//! ```text
+//! program CHECKSUM            checksum of the golf encoding of the program
+//! head PARENT CHILD LEFT RIGHT
+//! position BLOCK IP           next instruction to execute
+//! frame BLOCK IP [HEAD]       instruction that started each frame on the
+//!                             call stack, outermost first, along with the
+//!                             head to restore if it is a fork
+//! mode MODE                   current I/O mode
+//! input BITS                  bits of the current input unit not yet read
+//! output MODE BITS            mode and bits of the current output unit
+//! ```
+//!
+//! Unread input and the state of the random source are not saved.
+
+use std::rc::Rc;
+
+use super::{Frame, FrameKind, IOMode, Runtime};
+use crate::metatape::program::{Instruction, InstructionBlock, Program};
+use crate::metatape::tape::{Head, TapeReader, TapeWriter};
+
+const HEADER: &str = "metatape state 1";
+
+impl Runtime {
+    /// Returns the state of the runtime as text, which can be loaded by
+    /// `Runtime::load()` to continue running the same program later.
+    pub fn save(&self) -> String {
+        let blocks = program_blocks(&self.program);
+        let block_id = |block: &InstructionBlock| {
+            blocks
+                .iter()
+                .position(|b| Rc::ptr_eq(b, block))
+                .expect("Block is not in the program")
+        };
+
+        let mut out = format!("{}\nprogram {:016x}\n", HEADER, checksum(&self.program));
+        let mut writer = TapeWriter::default();
+        let head = writer.write_head(&mut out, &self.head);
+        out.push_str(&format!("head {}\n", head));
+        out.push_str(&format!(
+            "position {} {}\n",
+            block_id(&self.executing_block),
+            self.instruction_pointer,
+        ));
+        for frame in &self.call_stack {
+            let mut line = format!(
+                "frame {} {}",
+                block_id(&frame.return_block),
+                frame.return_instruction_pointer,
+            );
+            if let Some(fork_head) = &frame.fork_head {
+                line.push(' ');
+                line.push_str(&writer.write_head(&mut out, fork_head));
+            }
+            out.push_str(&line);
+            out.push('\n');
+        }
+        out.push_str(&format!("mode {}\n", self.input_buffer.mode));
+        out.push_str(&format!(
+            "input {}\n",
+            bits_to_string(&self.input_buffer.pending_bits())
+        ));
+        out.push_str(&format!(
+            "output {} {}\n",
+            self.output_buffer.unit_mode(),
+            bits_to_string(&self.output_buffer.pending_bits()),
+        ));
+        out
+    }
+
+    /// Loads state saved by `Runtime::save()`, which must have been saved
+    /// while running the same program (ignoring comments and whitespace).
+    /// Input and random bits are read from the runtime's current sources.
+    pub fn load(&mut self, saved: &str) -> Result<(), String> {
+        let mut lines = saved.lines().enumerate();
+        if lines.next().map(|(_, line)| line) != Some(HEADER) {
+            return Err("Not a saved Metatape state".to_owned());
+        }
+        let mut state = SavedState::default();
+        let blocks = program_blocks(&self.program);
+        for (idx, line) in lines {
+            let words: Vec<&str> = line.split_whitespace().collect();
+            state
+                .read_line(&words, &self.program, &blocks)
+                .map_err(|message| format!("Line {}: {}", idx + 1, message))?;
+        }
+        let missing = |item: &str| format!("Missing {}", item);
+        if !state.program {
+            return Err(missing("program"));
+        }
+        let head = state.head.ok_or_else(|| missing("head"))?;
+        let (executing_block, instruction_pointer) =
+            state.position.ok_or_else(|| missing("position"))?;
+        let mode = state.mode.ok_or_else(|| missing("mode"))?;
+        let input_bits = state.input.ok_or_else(|| missing("input"))?;
+        let (output_mode, output_bits) = state.output.ok_or_else(|| missing("output"))?;
+
+        // Check that each frame started the next one.
+        let started_blocks = state.frames.iter().map(|(_, block)| block);
+        let next_blocks = state
+            .frames
+            .iter()
+            .skip(1)
+            .map(|(frame, _)| &frame.return_block)
+            .chain(std::iter::once(&executing_block));
+        if !started_blocks
+            .zip(next_blocks)
+            .all(|(a, b)| Rc::ptr_eq(a, b))
+        {
+            return Err("Call stack does not match the program".to_owned());
+        }
+
+        self.output_buffer
+            .set_pending_bits(output_mode, &output_bits)?;
+        self.input_buffer.set_pending_bits(&input_bits);
+        self.set_io_mode(mode);
+        self.head = head;
+        self.executing_block = executing_block;
+        self.instruction_pointer = instruction_pointer;
+        self.call_stack = state.frames.into_iter().map(|(frame, _)| frame).collect();
+        // Logged bits were read before the state was saved.
+        self.input_log.clear();
+        self.random_log.clear();
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct SavedState {
+    /// Whether the program checksum has been checked.
+    program: bool,
+    tapes: TapeReader,
+    head: Option<Head>,
+    position: Option<(InstructionBlock, usize)>,
+    /// Each frame, along with the block that it started.
+    frames: Vec<(Frame, InstructionBlock)>,
+    mode: Option<IOMode>,
+    input: Option<Vec<bool>>,
+    output: Option<(IOMode, Vec<bool>)>,
+}
+
+impl SavedState {
+    fn read_line(
+        &mut self,
+        words: &[&str],
+        program: &Program,
+        blocks: &[&InstructionBlock],
+    ) -> Result<(), String> {
+        let (keyword, args) = match words.split_first() {
+            Some(split) => split,
+            None => return Ok(()),
+        };
+        let block = |s: &str| {
+            s.parse()
+                .ok()
+                .and_then(|idx: usize| blocks.get(idx))
+                .map(|&block| block.clone())
+                .ok_or_else(|| format!("Invalid block {:?}", s))
+        };
+        let number = |s: &str| {
+            s.parse::<usize>()
+                .map_err(|_| format!("Invalid number {:?}", s))
+        };
+        match (*keyword, args) {
+            ("program", [sum]) => {
+                if *sum != format!("{:016x}", checksum(program)) {
+                    return Err("State was saved while running a different program".to_owned());
+                }
+                self.program = true;
+            }
+            ("tape", args) => self.tapes.read_tape(args)?,
+            ("cell", args) => self.tapes.read_cell(args)?,
+            ("head", args) => self.head = Some(self.tapes.read_head(args)?),
+            ("position", [block_id, ip]) => {
+                let (block, ip) = (block(block_id)?, number(ip)?);
+                if ip > block.len() {
+                    return Err(format!("Invalid instruction pointer {}", ip));
+                }
+                self.position = Some((block, ip));
+            }
+            ("frame", [block_id, ip, head @ ..]) => {
+                let (block, ip) = (block(block_id)?, number(ip)?);
+                let (source_idx, instruction) = block
+                    .get(ip)
+                    .ok_or_else(|| format!("Invalid instruction pointer {}", ip))?;
+                let (kind, started_block) = match instruction {
+                    Instruction::Block(started) => (FrameKind::Block, started.clone()),
+                    Instruction::Call(name, call_id) => {
+                        let subroutine = program
+                            .linked_call(*call_id)
+                            .ok_or_else(|| format!("Subroutine {:?} is not defined", name))?;
+                        (FrameKind::Call(name.clone()), subroutine.clone())
+                    }
+                    Instruction::Fork(started) => (FrameKind::Fork, started.clone()),
+                    _ => return Err("Frame does not start at a block, call, or fork".to_owned()),
+                };
+                let fork_head = match (&kind, head) {
+                    (FrameKind::Fork, head) => Some(self.tapes.read_head(head)?),
+                    (_, []) => None,
+                    (_, _) => return Err("Only forks save the head".to_owned()),
+                };
+                let frame = Frame {
+                    kind,
+                    source_idx: *source_idx,
+                    return_block: block,
+                    return_instruction_pointer: ip,
+                    fork_head,
+                };
+                self.frames.push((frame, started_block));
+            }
+            ("mode", [mode]) => self.mode = Some(mode.parse()?),
+            ("input", [bits]) => {
+                let bits = bits_from_str(bits)?;
+                if bits.len() > 64 {
+                    return Err(format!("{} pending input bits is too many", bits.len()));
+                }
+                self.input = Some(bits);
+            }
+            ("output", [mode, bits]) => self.output = Some((mode.parse()?, bits_from_str(bits)?)),
+            _ => return Err(format!("Invalid line {:?}", words.join(" "))),
+        }
+        Ok(())
+    }
+}
+
+/// Returns every block in a program: the main program, then each subroutine
+/// in order of name, each followed by the blocks and forks inside it.
+fn program_blocks(program: &Program) -> Vec<&InstructionBlock> {
+    fn add_blocks<'a>(blocks: &mut Vec<&'a InstructionBlock>, block: &'a InstructionBlock) {
+        blocks.push(block);
+        for (_, instruction) in block.iter() {
+            if let Instruction::Block(inner) | Instruction::Fork(inner) = instruction {
+                add_blocks(blocks, inner);
+            }
+        }
+    }
+    let mut blocks = vec![];
+    add_blocks(&mut blocks, &program.instructions);
+    let mut names: Vec<&String> = program.subroutines.keys().collect();
+    names.sort();
+    for name in names {
+        add_blocks(&mut blocks, &program.subroutines[name]);
+    }
+    blocks
+}
+
+/// Returns a 64-bit FNV-1a hash of the golf encoding of a program, which
+/// doesn't depend on comments or whitespace.
+fn checksum(program: &Program) -> u64 {
+    crate::metatape::golf_encode(program)
+        .iter()
+        .fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+            (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+        })
+}
+
+fn bits_to_string(bits: &[bool]) -> String {
+    if bits.is_empty() {
+        "-".to_owned()
+    } else {
+        bits.iter()
+            .map(|&bit| if bit { '1' } else { '0' })
+            .collect()
+    }
+}
+
+fn bits_from_str(s: &str) -> Result<Vec<bool>, String> {
+    if s == "-" {
+        return Ok(vec![]);
+    }
+    s.chars()
+        .map(|c| match c {
+            '0' => Ok(false),
+            '1' => Ok(true),
+            _ => Err(format!("Invalid bit {:?}", c)),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::io::{Cursor, Read};
+    use std::rc::Rc;
+
+    use crate::metatape::runtime::io::SharedBuffer;
+    use crate::metatape::{parse_str, IOMode, Runtime, RuntimeError};
+
+    const INPUT: &str = "10111000";
+
+    /// Input that can be shared between runtimes.
+    #[derive(Clone)]
+    struct SharedInput(Rc<RefCell<Cursor<&'static str>>>);
+
+    impl Read for SharedInput {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().read(buf)
+        }
+    }
+
+    fn runtime(source: &str, input: &SharedInput, output: &SharedBuffer) -> Runtime {
+        Runtime::new(parse_str(source).unwrap())
+            .with_io_mode(IOMode::Ascii)
+            .with_input(input.clone())
+            .with_output(output.clone())
+            .with_buffered_output(false)
+    }
+
+    /// Runs a program, saving and loading it into a new runtime at each halt,
+    /// and checks that the output is the same as running it without stopping.
+    fn assert_resumes(source: &str) {
+        let input = SharedInput(Rc::new(RefCell::new(Cursor::new(INPUT))));
+        let expected = runtime(source, &input, &SharedBuffer::default())
+            .run_to_completion(INPUT)
+            .unwrap();
+        let output = SharedBuffer::default();
+        let mut runtime = runtime(source, &input, &output);
+        let mut halts = 0;
+        loop {
+            match runtime.step() {
+                Ok(_) => (),
+                Err(RuntimeError::Halt) => {
+                    halts += 1;
+                    let _ = runtime.unhalt();
+                    let saved = runtime.save();
+                    let head = runtime.get_head().clone();
+                    let call_depth = runtime.call_depth();
+                    runtime = self::runtime(source, &input, &output);
+                    runtime.load(&saved).unwrap();
+                    assert_eq!(runtime.get_head(), &head);
+                    assert_eq!(runtime.call_depth(), call_depth);
+                    assert_eq!(runtime.save(), saved);
+                }
+                Err(RuntimeError::EndOfProgram) => break,
+                Err(error) => panic!("{:?}", error),
+            }
+        }
+        assert!(halts > 0);
+        assert_eq!(*output.0.borrow(), expected);
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        assert_resumes("ex > ex < h o > o h o");
+        assert_resumes("%{utf8} ex o o h o i o o h x o");
+        assert_resumes("i h io i h io");
+        assert_resumes("%{msb} i h i o i h o");
+        assert_resumes("!a h !a o @ a { {ex> f{ e h <x h n} o} h }");
+        assert_resumes("%{msb} ex >e >ex <<< ^{>*} f{ex x h n} e h x o %{ascii} h o");
+    }
+
+    #[test]
+    fn test_invalid() {
+        let (input, output) = (SharedInput(Default::default()), SharedBuffer::default());
+        let saved = runtime("!a @ a { h }", &input, &output).save();
+        let mut other = runtime("!b @ b { h }", &input, &output);
+        assert_eq!(
+            other.load(&saved),
+            Err("Line 2: State was saved while running a different program".to_owned()),
+        );
+        let mut same = runtime("!a /* comment */ @ a {h}", &input, &output);
+        assert_eq!(same.load(&saved), Ok(()));
+        assert!(same.load("").is_err());
+        let without_program: Vec<_> = saved
+            .lines()
+            .filter(|line| !line.starts_with("program"))
+            .collect();
+        assert_eq!(
+            same.load(&without_program.join("\n")),
+            Err("Missing program".to_owned())
+        );
+        assert_eq!(
+            same.load(&saved.replace("head", "")),
+            Err("Line 3: Invalid line \"- - - -\"".to_owned())
+        );
+        assert_eq!(
+            same.load(&saved.replace("position 0 0", "position 0 2")),
+            Err("Line 4: Invalid instruction pointer 2".to_owned()),
+        );
+        assert_eq!(
+            same.load(&saved.replace("position 0 0", "position 1 0\nframe 1 0")),
+            Err("Line 5: Frame does not start at a block, call, or fork".to_owned()),
+        );
+        assert_eq!(
+            same.load(&saved.replace("position 0 0", "position 1 0\nframe 0 0")),
+            Ok(()),
+        );
+        assert_eq!(
+            same.load(&saved.replace("position 0 0", "position 0 0\nframe 0 0")),
+            Err("Call stack does not match the program".to_owned()),
+        );
+    }
+}
@@ -0,0 +1,133 @@
+//! Snapshots of the state of a runtime, which can be restored later.
+
+use super::io::OutputPosition;
+use super::{Frame, IOMode, Runtime};
+use crate::metatape::program::{Instruction, InstructionBlock};
+use crate::metatape::tape::Head;
+
+/// State of a runtime at one point in time. Taking a snapshot is cheap,
+/// because the tape is shared rather than copied.
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    pub head: Head,
+    pub executing_block: InstructionBlock,
+    pub instruction_pointer: usize,
+    pub call_stack: Vec<Frame>,
+    /// Number of input bits read so far.
+    pub input_position: usize,
+    pub input_mode: IOMode,
+    /// Number of random bits generated so far.
+    pub random_position: usize,
+    output: OutputPosition,
+}
+
+impl Snapshot {
+    /// Returns the instruction that will be executed next, along with its
+    /// position in the program's source.
+    pub fn instruction(&self) -> Option<&(usize, Instruction)> {
+        self.executing_block.get(self.instruction_pointer)
+    }
+}
+
+/// Bits read from the input or the random source, so that the same bits can
+/// be read again after restoring a snapshot.
+#[derive(Debug, Default)]
+pub(super) struct BitLog {
+    pub(super) enabled: bool,
+    bits: Vec<bool>,
+    /// Number of bits read so far, including bits read again from the log.
+    position: usize,
+}
+
+impl BitLog {
+    /// Forgets all of the bits in the log.
+    pub(super) fn clear(&mut self) {
+        self.bits.clear();
+        self.position = 0;
+    }
+
+    /// Returns the next bit from the log, or reads a new bit if the end of
+    /// the log has been reached.
+    pub(super) fn next_bit(&mut self, read: impl FnOnce() -> Option<bool>) -> Option<bool> {
+        let bit = match self.bits.get(self.position) {
+            Some(&bit) => bit,
+            None if self.enabled => {
+                let bit = read()?;
+                self.bits.push(bit);
+                bit
+            }
+            None => return read(),
+        };
+        self.position += 1;
+        Some(bit)
+    }
+}
+
+impl Runtime {
+    /// Sets whether to record input and random bits, so that a program reads
+    /// the same bits again after restoring a snapshot instead of reading new
+    /// ones. This is off by default.
+    pub fn set_replayable(&mut self, replayable: bool) {
+        self.input_log.enabled = replayable;
+        self.random_log.enabled = replayable;
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            head: self.head.clone(),
+            executing_block: self.executing_block.clone(),
+            instruction_pointer: self.instruction_pointer,
+            call_stack: self.call_stack.clone(),
+            input_position: self.input_log.position,
+            input_mode: self.input_buffer.mode,
+            random_position: self.random_log.position,
+            output: self.output_buffer.position(),
+        }
+    }
+
+    /// Returns to the state in a snapshot. Output that was written after the
+    /// snapshot is not written again, and if the runtime is replayable then
+    /// input and random bits are read again from the log.
+    pub fn restore(&mut self, snapshot: &Snapshot) {
+        self.head = snapshot.head.clone();
+        self.executing_block = snapshot.executing_block.clone();
+        self.instruction_pointer = snapshot.instruction_pointer;
+        self.call_stack = snapshot.call_stack.clone();
+        self.input_log.position = snapshot.input_position.min(self.input_log.bits.len());
+        self.input_buffer.mode = snapshot.input_mode;
+        self.random_log.position = snapshot.random_position.min(self.random_log.bits.len());
+        self.output_buffer.rewind(&snapshot.output);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::metatape::{parse_str, BitSequence, Runtime};
+
+    #[test]
+    fn test_restore() {
+        let program = parse_str("ex i o ? o i o").unwrap();
+        let output = crate::metatape::runtime::io::SharedBuffer::default();
+        let mut runtime = Runtime::new(program)
+            .with_io_mode(crate::metatape::IOMode::Ascii)
+            .with_input_bytes("10")
+            .with_output(output.clone())
+            .with_random_source("1".parse::<BitSequence>().unwrap());
+        runtime.set_replayable(true);
+        runtime.set_buffered_output(false);
+        let snapshot = runtime.snapshot();
+        for _ in 0..8 {
+            runtime.step().unwrap();
+        }
+        assert_eq!(*output.0.borrow(), b"110");
+        let end = runtime.snapshot();
+
+        // Input and random bits are replayed, and output isn't repeated.
+        runtime.restore(&snapshot);
+        for _ in 0..8 {
+            runtime.step().unwrap();
+        }
+        assert_eq!(*output.0.borrow(), b"110");
+        assert_eq!(runtime.get_head(), &end.head);
+    }
+}
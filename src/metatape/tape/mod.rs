@@ -2,6 +2,12 @@ use std::fmt;
 use std::fmt::Write;
 use std::sync::Arc;
 
+mod render;
+mod serialize;
+
+pub use render::RenderOptions;
+pub(crate) use serialize::{TapeReader, TapeWriter};
+
 #[derive(Clone)]
 pub struct Head {
     parent: Option<Arc<Tape>>, // extends up
@@ -10,6 +16,7 @@ pub struct Head {
     right: Option<Arc<Cell>>,  // extends right
 }
 
+#[derive(Default)]
 struct Tape {
     next: Option<Arc<Tape>>,  // extends up/down
     left: Option<Arc<Cell>>,  // extends left
@@ -74,30 +81,12 @@ fn fmt_cell_depth(f: &mut fmt::Formatter<'_>, child: &Option<Arc<Tape>>) -> fmt:
     })
 }
 
-impl std::default::Default for Tape {
-    fn default() -> Self {
-        Self {
-            next: None,
-            left: None,
-            right: None,
-        }
-    }
-}
-
+#[derive(Default)]
 struct Cell {
     child: Option<Arc<Tape>>, // extends down
     next: Option<Arc<Cell>>,  // extends left/right
 }
 
-impl std::default::Default for Cell {
-    fn default() -> Self {
-        Self {
-            child: None,
-            next: None,
-        }
-    }
-}
-
 impl Head {
     pub fn new() -> Head {
         Head {
@@ -194,6 +183,14 @@ impl Head {
     pub fn copy_child_from(&self, other: &Head) -> Head {
         self.set_child(other.child.clone())
     }
+
+    /// Returns whether the current cells of two heads have the same contents.
+    pub fn child_eq(&self, other: &Head) -> bool {
+        match (&self.child, &other.child) {
+            (Some(a), Some(b)) => Arc::ptr_eq(a, b) || a == b,
+            (a, b) => a.is_none() && b.is_none(),
+        }
+    }
 }
 
 impl Tape {
@@ -204,3 +201,150 @@ impl Tape {
         }
     }
 }
+
+impl fmt::Debug for Head {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#}", self)
+    }
+}
+
+/// Two heads are equal if no sequence of instructions can tell them apart, so
+/// null cells at the ends of a tape and empty parent tapes are ignored.
+impl PartialEq for Head {
+    fn eq(&self, other: &Self) -> bool {
+        parents_eq(&self.parent, &other.parent)
+            && self.child == other.child
+            && cells_eq(&self.left, &other.left)
+            && cells_eq(&self.right, &other.right)
+    }
+}
+
+impl Eq for Head {}
+
+/// Compares the tapes in two non-null cells.
+impl PartialEq for Tape {
+    fn eq(&self, other: &Self) -> bool {
+        self.next == other.next
+            && cells_eq(&self.left, &other.left)
+            && cells_eq(&self.right, &other.right)
+    }
+}
+
+fn parents_eq(a: &Option<Arc<Tape>>, b: &Option<Arc<Tape>>) -> bool {
+    match (a, b) {
+        (Some(a), Some(b)) if Arc::ptr_eq(a, b) => true,
+        (Some(a), Some(b)) => {
+            parents_eq(&a.next, &b.next)
+                && cells_eq(&a.left, &b.left)
+                && cells_eq(&a.right, &b.right)
+        }
+        (None, None) => true,
+        (Some(tape), None) | (None, Some(tape)) => {
+            parents_eq(&tape.next, &None)
+                && cells_eq(&tape.left, &None)
+                && cells_eq(&tape.right, &None)
+        }
+    }
+}
+
+fn cells_eq(a: &Option<Arc<Cell>>, b: &Option<Arc<Cell>>) -> bool {
+    match (a, b) {
+        (Some(a), Some(b)) if Arc::ptr_eq(a, b) => true,
+        (Some(a), Some(b)) => a.child == b.child && cells_eq(&a.next, &b.next),
+        (None, None) => true,
+        (Some(cell), None) | (None, Some(cell)) => {
+            cell.child.is_none() && cells_eq(&cell.next, &None)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    use std::sync::Arc;
+
+    use super::{Cell, Head, Tape};
+
+    /// Returns a head that has wandered around randomly, entering and exiting
+    /// cells and setting some of them to null.
+    fn random_head(rng: &mut StdRng) -> Head {
+        let mut head = Head::new();
+        for _ in 0..rng.gen_range(0, 100) {
+            head = match rng.gen_range(0, 5) {
+                0 => head.move_left(),
+                1 => head.move_right(),
+                2 => head.enter(),
+                3 => head.exit(),
+                _ => head.null_child(),
+            };
+        }
+        head
+    }
+
+    fn for_random_heads(test: impl Fn(Head)) {
+        let mut rng = StdRng::seed_from_u64(0);
+        for _ in 0..1000 {
+            test(random_head(&mut rng));
+        }
+    }
+
+    #[test]
+    fn test_move_round_trip() {
+        for_random_heads(|head| {
+            assert_eq!(head.move_left().move_right(), head);
+            assert_eq!(head.move_right().move_left(), head);
+        });
+    }
+
+    #[test]
+    fn test_exit_enter_round_trip() {
+        for_random_heads(|head| {
+            assert_eq!(head.exit().enter(), head);
+        });
+    }
+
+    #[test]
+    fn test_enter_exit_round_trip() {
+        for_random_heads(|head| {
+            let round_trip = head.enter().exit();
+            assert!(round_trip.has_child());
+            if head.has_child() {
+                assert_eq!(round_trip, head);
+            } else {
+                // Entering a null cell creates an empty tape.
+                assert_eq!(round_trip, head.exit().enter().enter().exit());
+                assert_eq!(round_trip.null_child(), head);
+            }
+        });
+    }
+
+    #[test]
+    fn test_null_child() {
+        for_random_heads(|head| {
+            let null = head.null_child();
+            assert!(!null.has_child());
+            assert_eq!(null.null_child(), null);
+            assert_eq!(null.copy_child_from(&head), head);
+            assert_eq!(head == null, !head.has_child());
+        });
+    }
+
+    #[test]
+    fn test_trailing_null_cells_are_ignored() {
+        let head = Head::new();
+        let padded = Head {
+            parent: Some(Arc::new(Tape::default())),
+            right: Some(Arc::new(Cell {
+                child: None,
+                next: Some(Arc::new(Cell::default())),
+            })),
+            ..Head::new()
+        };
+        assert_eq!(padded, head);
+        assert_eq!(padded.move_right().move_right(), head);
+        assert_ne!(padded.move_right().enter().exit(), head);
+        assert_ne!(head.enter().exit(), head);
+    }
+}
@@ -0,0 +1,253 @@
+//! Rendering the whole structure of a tape, including parents and children.
+
+use std::fmt::Write;
+use std::sync::Arc;
+
+use super::{Cell, Head, Tape};
+
+/// Options that control how much of a tape is rendered by `Head::render()`.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct RenderOptions {
+    /// Number of parent tapes to show around the head's tape.
+    pub parents: usize,
+    /// Number of levels of children to show below the head's tape. Cells
+    /// with children that are not shown are rendered as `[...]`.
+    pub children: usize,
+}
+
+/// Tape with null cells at each end removed (except for the cursor).
+struct TapeView {
+    cells: Vec<CellView>,
+    /// Index of the cell where the head is, or would be after entering the
+    /// tape.
+    cursor: usize,
+}
+
+enum CellView {
+    Null,
+    Child(TapeView),
+    /// A cell whose child is too deep to show.
+    Hidden,
+}
+
+impl Head {
+    /// Renders the tape as nested brackets, with `_` for each null cell and
+    /// `*` before the cell under the head. For example, `_ [[_] *_] _` is
+    /// three cells, the middle of which contains a tape containing the head.
+    /// Null cells at the ends of each tape are left out.
+    pub fn render(&self, options: &RenderOptions) -> String {
+        let (view, levels_above_head) = self.view(options.parents, options.children);
+        let mut ret = String::new();
+        render_tape(&mut ret, &view, Some(levels_above_head));
+        ret
+    }
+
+    /// Returns a Graphviz graph of the whole tape. Each tape is a row of
+    /// cells, with an arrow from each non-null cell to its child. The cell
+    /// under the head is blue, and the cell in each other tape where the head
+    /// would be after entering it is grey.
+    pub fn to_dot(&self) -> String {
+        let (view, levels_above_head) = self.view(usize::MAX, usize::MAX);
+        let mut ret = String::from("digraph tape {\n    node [shape=plaintext];\n");
+        let mut next_id = 0;
+        dot_tape(&mut ret, &view, &mut next_id, Some(levels_above_head));
+        ret.push_str("}\n");
+        ret
+    }
+
+    /// Returns the outermost tape to show, along with the number of levels
+    /// between it and the head.
+    fn view(&self, parents: usize, children: usize) -> (TapeView, usize) {
+        let mut view = tape_view(
+            &self.left,
+            content_view(&self.child, children),
+            &self.right,
+            children,
+        );
+        let mut levels_above_head = 0;
+        let mut parent = &self.parent;
+        while let Some(tape) = parent {
+            if levels_above_head == parents {
+                break;
+            }
+            let depth = children.saturating_add(levels_above_head + 1);
+            view = tape_view(&tape.left, CellView::Child(view), &tape.right, depth);
+            levels_above_head += 1;
+            parent = &tape.next;
+        }
+        (view, levels_above_head)
+    }
+}
+
+/// Returns a view of a tape, given the cells on each side of the cursor and
+/// the number of levels of children to show.
+fn tape_view(
+    left: &Option<Arc<Cell>>,
+    cursor: CellView,
+    right: &Option<Arc<Cell>>,
+    depth: usize,
+) -> TapeView {
+    let mut cells: Vec<CellView> = cell_list(left)
+        .map(|cell| content_view(&cell.child, depth))
+        .collect();
+    cells.reverse();
+    let mut cursor_idx = cells.len();
+    cells.push(cursor);
+    cells.extend(cell_list(right).map(|cell| content_view(&cell.child, depth)));
+
+    while cells.len() > cursor_idx + 1 && matches!(cells.last(), Some(CellView::Null)) {
+        cells.pop();
+    }
+    let leading_nulls = cells[..cursor_idx]
+        .iter()
+        .take_while(|cell| matches!(cell, CellView::Null))
+        .count();
+    cells.drain(..leading_nulls);
+    cursor_idx -= leading_nulls;
+    TapeView {
+        cells,
+        cursor: cursor_idx,
+    }
+}
+
+fn content_view(content: &Option<Arc<Tape>>, depth: usize) -> CellView {
+    match content {
+        None => CellView::Null,
+        Some(_) if depth == 0 => CellView::Hidden,
+        Some(tape) => CellView::Child(tape_view(
+            &tape.left,
+            content_view(&tape.next, depth - 1),
+            &tape.right,
+            depth - 1,
+        )),
+    }
+}
+
+/// Iterates over a list of cells, starting with the one closest to the
+/// cursor.
+fn cell_list(mut cell: &Option<Arc<Cell>>) -> impl Iterator<Item = &Arc<Cell>> {
+    std::iter::from_fn(move || {
+        let ret = cell.as_ref()?;
+        cell = &ret.next;
+        Some(ret)
+    })
+}
+
+/// Renders a tape. `levels_above_head` is the number of levels between this
+/// tape and the head, if the head is inside it.
+fn render_tape(ret: &mut String, view: &TapeView, levels_above_head: Option<usize>) {
+    for (idx, cell) in view.cells.iter().enumerate() {
+        if idx > 0 {
+            ret.push(' ');
+        }
+        let levels_above_head = levels_above_head.filter(|_| idx == view.cursor);
+        if levels_above_head == Some(0) {
+            ret.push('*');
+        }
+        match cell {
+            CellView::Null => ret.push('_'),
+            CellView::Hidden => ret.push_str("[...]"),
+            CellView::Child(child) => {
+                ret.push('[');
+                render_tape(ret, child, levels_above_head.and_then(|n| n.checked_sub(1)));
+                ret.push(']');
+            }
+        }
+    }
+}
+
+/// Writes a node for a tape and its children, returning the ID of the node.
+fn dot_tape(
+    ret: &mut String,
+    view: &TapeView,
+    next_id: &mut usize,
+    levels_above_head: Option<usize>,
+) -> usize {
+    let id = *next_id;
+    *next_id += 1;
+    let mut row = String::new();
+    let mut edges = vec![];
+    for (idx, cell) in view.cells.iter().enumerate() {
+        let color = match levels_above_head {
+            Some(0) if idx == view.cursor => " BGCOLOR=\"lightblue\"",
+            _ if idx == view.cursor => " BGCOLOR=\"lightgrey\"",
+            _ => "",
+        };
+        let _ = write!(row, "<TD PORT=\"c{}\" WIDTH=\"20\"{}></TD>", idx, color);
+        if let CellView::Child(child) = cell {
+            let child_levels = levels_above_head
+                .filter(|_| idx == view.cursor)
+                .and_then(|n| n.checked_sub(1));
+            let child_id = dot_tape(ret, child, next_id, child_levels);
+            edges.push(format!("    tape{}:c{} -> tape{};\n", id, idx, child_id));
+        }
+    }
+    let _ = writeln!(
+        ret,
+        "    tape{} [label=<<TABLE BORDER=\"0\" CELLBORDER=\"1\" CELLSPACING=\"0\"><TR>{}</TR></TABLE>>];",
+        id, row,
+    );
+    for edge in edges {
+        ret.push_str(&edge);
+    }
+    id
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RenderOptions;
+    use crate::metatape::{parse_str, Head, Runtime};
+
+    fn head_after(source: &str) -> Head {
+        let mut runtime = Runtime::new(parse_str(source).unwrap());
+        while runtime.step().is_ok() {}
+        runtime.get_head().clone()
+    }
+
+    fn render(source: &str, parents: usize, children: usize) -> String {
+        head_after(source).render(&RenderOptions { parents, children })
+    }
+
+    #[test]
+    fn test_render() {
+        assert_eq!(render("", 0, 0), "*_");
+        assert_eq!(render("ex > ex >>", 0, 0), "[...] [...] _ *_");
+        assert_eq!(render("ex > ex >>", 0, 1), "[_] [_] _ *_");
+        assert_eq!(render("< ex", 0, 1), "*[_]");
+        assert_eq!(render("e > ex < x >", 0, 2), "[_ [_]] *_");
+        // The cursor of a child tape is not marked.
+        assert_eq!(render("e > ex < x >", 0, 1), "[_ [...]] *_");
+    }
+
+    #[test]
+    fn test_render_parents() {
+        let source = "ex > ex e > ex <";
+        assert_eq!(render(source, 0, 0), "*_ [...]");
+        assert_eq!(render(source, 1, 0), "[_] [*_ [...]]");
+        assert_eq!(render(source, 1, 1), "[_] [*_ [_]]");
+        assert_eq!(render(source, 5, 1), "[_] [*_ [_]]");
+        assert_eq!(render("ex < e e", 1, 0), "[*_]");
+        assert_eq!(render("ex < e e", 2, 0), "[[*_]] [_]");
+    }
+
+    #[test]
+    fn test_dot() {
+        let dot = head_after("e > ex < x").to_dot();
+        assert_eq!(
+            dot,
+            "digraph tape {\n    node [shape=plaintext];\n    \
+             tape2 [label=<<TABLE BORDER=\"0\" CELLBORDER=\"1\" CELLSPACING=\"0\"><TR>\
+             <TD PORT=\"c0\" WIDTH=\"20\" BGCOLOR=\"lightgrey\"></TD>\
+             </TR></TABLE>>];\n    \
+             tape1 [label=<<TABLE BORDER=\"0\" CELLBORDER=\"1\" CELLSPACING=\"0\"><TR>\
+             <TD PORT=\"c0\" WIDTH=\"20\" BGCOLOR=\"lightgrey\"></TD>\
+             <TD PORT=\"c1\" WIDTH=\"20\"></TD>\
+             </TR></TABLE>>];\n    \
+             tape1:c1 -> tape2;\n    \
+             tape0 [label=<<TABLE BORDER=\"0\" CELLBORDER=\"1\" CELLSPACING=\"0\"><TR>\
+             <TD PORT=\"c0\" WIDTH=\"20\" BGCOLOR=\"lightblue\"></TD>\
+             </TR></TABLE>>];\n    \
+             tape0:c0 -> tape1;\n}\n",
+        );
+    }
+}
@@ -0,0 +1,274 @@
+//! Text format for tapes that keeps tapes and cells that are shared between
+//! heads (or within one head) shared when they are read back.
+//!
+//! Each tape or cell is written once, on a line of its own, after everything
+//! it refers to. Lines are numbered from 0 in the order they are written, and
+//! refer to each other by number, with `-` for nothing:
+//!
+//! ```text
+//! tape NEXT LEFT RIGHT
+//! cell CHILD NEXT
+//! ```
+//!
+//! A head is written as `PARENT CHILD LEFT RIGHT`, in the same way.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use super::{Cell, Head, Tape};
+
+#[derive(Copy, Clone)]
+enum NodeRef<'a> {
+    Tape(&'a Arc<Tape>),
+    Cell(&'a Arc<Cell>),
+}
+
+impl NodeRef<'_> {
+    fn ptr(self) -> *const () {
+        match self {
+            NodeRef::Tape(tape) => Arc::as_ptr(tape) as *const (),
+            NodeRef::Cell(cell) => Arc::as_ptr(cell) as *const (),
+        }
+    }
+}
+
+#[derive(Clone)]
+enum Node {
+    Tape(Arc<Tape>),
+    Cell(Arc<Cell>),
+}
+
+/// Writes tapes and cells, remembering which ones have already been written.
+#[derive(Default)]
+pub(crate) struct TapeWriter {
+    ids: HashMap<*const (), usize>,
+}
+
+impl TapeWriter {
+    /// Writes the tapes and cells in a head that have not been written yet,
+    /// and returns the head's references to them.
+    pub(crate) fn write_head(&mut self, out: &mut String, head: &Head) -> String {
+        let parts = [
+            head.parent.as_ref().map(NodeRef::Tape),
+            head.child.as_ref().map(NodeRef::Tape),
+            head.left.as_ref().map(NodeRef::Cell),
+            head.right.as_ref().map(NodeRef::Cell),
+        ];
+        let refs: Vec<String> = parts
+            .iter()
+            .map(|&node| self.write_node(out, node))
+            .collect();
+        refs.join(" ")
+    }
+
+    /// Writes a node and everything it refers to (if they have not been
+    /// written yet), and returns the reference to it. This doesn't recurse,
+    /// because lists of cells can be very long.
+    fn write_node(&mut self, out: &mut String, node: Option<NodeRef<'_>>) -> String {
+        let root = match node {
+            Some(node) => node,
+            None => return "-".to_owned(),
+        };
+        // Each node is visited once before its children are written, and
+        // once after.
+        let mut stack = vec![(root, false)];
+        while let Some((node, children_written)) = stack.pop() {
+            if self.ids.contains_key(&node.ptr()) {
+                continue;
+            }
+            let children = match node {
+                NodeRef::Tape(tape) => vec![
+                    tape.next.as_ref().map(NodeRef::Tape),
+                    tape.left.as_ref().map(NodeRef::Cell),
+                    tape.right.as_ref().map(NodeRef::Cell),
+                ],
+                NodeRef::Cell(cell) => vec![
+                    cell.child.as_ref().map(NodeRef::Tape),
+                    cell.next.as_ref().map(NodeRef::Cell),
+                ],
+            };
+            if children_written {
+                let refs: Vec<String> =
+                    children.iter().map(|&child| self.node_ref(child)).collect();
+                let keyword = match node {
+                    NodeRef::Tape(_) => "tape",
+                    NodeRef::Cell(_) => "cell",
+                };
+                out.push_str(&format!("{} {}\n", keyword, refs.join(" ")));
+                let id = self.ids.len();
+                self.ids.insert(node.ptr(), id);
+            } else {
+                stack.push((node, true));
+                stack.extend(children.into_iter().flatten().map(|child| (child, false)));
+            }
+        }
+        self.node_ref(Some(root))
+    }
+
+    fn node_ref(&self, node: Option<NodeRef<'_>>) -> String {
+        match node {
+            Some(node) => self.ids[&node.ptr()].to_string(),
+            None => "-".to_owned(),
+        }
+    }
+}
+
+/// Reads tapes and cells written by a `TapeWriter`.
+#[derive(Default)]
+pub(crate) struct TapeReader {
+    nodes: Vec<Node>,
+}
+
+impl TapeReader {
+    /// Reads the arguments of a `tape` line.
+    pub(crate) fn read_tape(&mut self, args: &[&str]) -> Result<(), String> {
+        match args {
+            [next, left, right] => {
+                let tape = Tape {
+                    next: self.tape(next)?,
+                    left: self.cell(left)?,
+                    right: self.cell(right)?,
+                };
+                self.nodes.push(Node::Tape(Arc::new(tape)));
+                Ok(())
+            }
+            _ => Err("Expected 3 references in tape".to_owned()),
+        }
+    }
+
+    /// Reads the arguments of a `cell` line.
+    pub(crate) fn read_cell(&mut self, args: &[&str]) -> Result<(), String> {
+        match args {
+            [child, next] => {
+                let cell = Cell {
+                    child: self.tape(child)?,
+                    next: self.cell(next)?,
+                };
+                self.nodes.push(Node::Cell(Arc::new(cell)));
+                Ok(())
+            }
+            _ => Err("Expected 2 references in cell".to_owned()),
+        }
+    }
+
+    /// Reads the references written by `TapeWriter::write_head()`.
+    pub(crate) fn read_head(&self, args: &[&str]) -> Result<Head, String> {
+        match args {
+            [parent, child, left, right] => Ok(Head {
+                parent: self.tape(parent)?,
+                child: self.tape(child)?,
+                left: self.cell(left)?,
+                right: self.cell(right)?,
+            }),
+            _ => Err("Expected 4 references in head".to_owned()),
+        }
+    }
+
+    fn node(&self, s: &str) -> Result<Option<Node>, String> {
+        if s == "-" {
+            return Ok(None);
+        }
+        s.parse()
+            .ok()
+            .and_then(|idx: usize| self.nodes.get(idx))
+            .cloned()
+            .map(Some)
+            .ok_or_else(|| format!("Invalid reference {:?}", s))
+    }
+
+    fn tape(&self, s: &str) -> Result<Option<Arc<Tape>>, String> {
+        match self.node(s)? {
+            None => Ok(None),
+            Some(Node::Tape(tape)) => Ok(Some(tape)),
+            Some(Node::Cell(_)) => Err(format!("Expected tape but {} is a cell", s)),
+        }
+    }
+
+    fn cell(&self, s: &str) -> Result<Option<Arc<Cell>>, String> {
+        match self.node(s)? {
+            None => Ok(None),
+            Some(Node::Cell(cell)) => Ok(Some(cell)),
+            Some(Node::Tape(_)) => Err(format!("Expected cell but {} is a tape", s)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::{TapeReader, TapeWriter};
+    use crate::metatape::tape::Head;
+
+    /// Writes some heads and reads them back.
+    fn round_trip(heads: &[&Head]) -> (String, Vec<Head>) {
+        let mut out = String::new();
+        let mut writer = TapeWriter::default();
+        let head_refs: Vec<String> = heads
+            .iter()
+            .map(|head| writer.write_head(&mut out, head))
+            .collect();
+        let mut reader = TapeReader::default();
+        for line in out.lines() {
+            let words: Vec<&str> = line.split(' ').collect();
+            match words[0] {
+                "tape" => reader.read_tape(&words[1..]).unwrap(),
+                _ => reader.read_cell(&words[1..]).unwrap(),
+            }
+        }
+        let heads = head_refs
+            .iter()
+            .map(|refs| {
+                reader
+                    .read_head(&refs.split(' ').collect::<Vec<_>>())
+                    .unwrap()
+            })
+            .collect();
+        (out, heads)
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let head = Head::new().enter().exit().move_left().exit();
+        let (out, heads) = round_trip(&[&head]);
+        assert_eq!(out, "tape - - -\ncell 0 -\ntape - - 1\n");
+        assert_eq!(heads, vec![head]);
+
+        let head = Head::new()
+            .move_left()
+            .move_right()
+            .exit()
+            .move_right()
+            .enter();
+        assert_eq!(round_trip(&[&head]).1, vec![head]);
+    }
+
+    #[test]
+    fn test_sharing() {
+        let a = Head::new().enter().exit();
+        let b = a.move_right().copy_child_from(&a).move_right();
+        let (out, heads) = round_trip(&[&a, &b]);
+        // The tape in `a` is only written once.
+        assert_eq!(out, "tape - - -\ncell 0 -\ncell 0 1\n");
+        assert_eq!(heads, vec![a, b]);
+        let shared = heads[0].child.as_ref().unwrap();
+        let left = heads[1].left.as_ref().unwrap();
+        assert!(Arc::ptr_eq(shared, left.child.as_ref().unwrap()));
+        assert!(Arc::ptr_eq(
+            shared,
+            left.next.as_ref().unwrap().child.as_ref().unwrap()
+        ));
+    }
+
+    #[test]
+    fn test_invalid() {
+        let mut reader = TapeReader::default();
+        assert!(reader.read_tape(&["0", "-", "-"]).is_err());
+        reader.read_tape(&["-", "-", "-"]).unwrap();
+        assert!(reader.read_cell(&["-"]).is_err());
+        assert!(reader.read_cell(&["-", "0"]).is_err());
+        reader.read_cell(&["0", "-"]).unwrap();
+        assert!(reader.read_head(&["-", "0", "1", "x"]).is_err());
+        assert!(reader.read_head(&["-", "0", "1", "-"]).is_ok());
+    }
+}
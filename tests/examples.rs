@@ -0,0 +1,165 @@
+//! Runs each program in `examples/` and compares its output to a golden file.
+//!
+//! Each case reads its input from `tests/examples/<case>.in` (if it exists)
+//! and compares its output to `tests/examples/<case>.out`. Set
+//! `METATAPE_BLESS=1` to overwrite the `.out` files with the actual output.
+//! Examples that are libraries are run using a program in `tests/examples`
+//! that loads them.
+
+use std::fs;
+use std::path::Path;
+
+use metatape::{
+    EofPolicy, MinifyOptions, ParseOptions, Program, Runtime, RuntimeError, SharedBuffer,
+};
+
+struct Case {
+    name: &'static str,
+    example: &'static str,
+    eof_policy: EofPolicy,
+    /// Maximum number of instructions to execute, for programs that never end.
+    max_steps: Option<usize>,
+    /// Program in `tests/examples` to run instead of the example, for
+    /// examples that only define subroutines.
+    main: Option<&'static str>,
+}
+
+const fn case(name: &'static str, example: &'static str) -> Case {
+    Case {
+        name,
+        example,
+        eof_policy: EofPolicy::Zero,
+        max_steps: None,
+        main: None,
+    }
+}
+
+const CASES: &[Case] = &[
+    case("99_bottles", "99_bottles.mt"),
+    case("bct", "bct.mt"),
+    Case {
+        max_steps: Some(1_000_000),
+        ..case("bct_cycle", "bct.mt")
+    },
+    Case {
+        eof_policy: EofPolicy::Flag,
+        ..case("cat_eof", "cat_eof.mt")
+    },
+    case("cat_no_null", "cat_no_null.mt"),
+    case("cat_null", "cat_null.mt"),
+    Case {
+        eof_policy: EofPolicy::Halt,
+        ..case("cat_simple", "cat_simple.mt")
+    },
+    Case {
+        max_steps: Some(10_000),
+        ..case("cat_simple_forever", "cat_simple.mt")
+    },
+    case("hello", "hello.mt"),
+    Case {
+        main: Some("integer_routines.mt"),
+        ..case("integer_routines", "integer_routines.mt")
+    },
+];
+
+fn load_example(case: &Case) -> Program {
+    let path = match case.main {
+        Some(main) => Path::new("tests/examples").join(main),
+        None => Path::new("examples").join(case.example),
+    };
+    metatape::program_from_file(path.to_str().unwrap(), &ParseOptions::default())
+        .unwrap_or_else(|errors| panic!("{}: {}", case.name, errors[0]))
+}
+
+fn run_case(case: &Case, program: Program) -> Vec<u8> {
+    let input =
+        fs::read(Path::new("tests/examples").join(format!("{}.in", case.name))).unwrap_or_default();
+    let output = SharedBuffer::default();
+    let mut runtime = Runtime::new(program)
+        .with_input_bytes(input)
+        .with_output(output.clone())
+        .with_eof_policy(case.eof_policy)
+        .with_seed(0);
+    let mut steps = 0;
+    while steps < case.max_steps.unwrap_or(usize::MAX) {
+        match runtime.step() {
+            Ok(_) => steps += 1,
+            Err(RuntimeError::Halt) => runtime.unhalt().unwrap(),
+            Err(RuntimeError::EndOfProgram) => break,
+            Err(err) => panic!("{}: {:?}", case.name, err),
+        }
+    }
+    if case.max_steps.is_none() {
+        assert!(
+            runtime.pending_output_bits().is_empty(),
+            "{}: output ended partway through a byte",
+            case.name,
+        );
+    }
+    runtime.flush_output();
+    output.0.take()
+}
+
+fn read_expected(case: &Case) -> Vec<u8> {
+    let out_path = Path::new("tests/examples").join(format!("{}.out", case.name));
+    fs::read(&out_path)
+        .unwrap_or_else(|err| panic!("{}: {}: {}", case.name, out_path.display(), err))
+}
+
+#[test]
+fn test_examples() {
+    let bless = std::env::var_os("METATAPE_BLESS").is_some();
+    let mut failures = vec![];
+    for case in CASES {
+        let actual = run_case(case, load_example(case));
+        let out_path = Path::new("tests/examples").join(format!("{}.out", case.name));
+        if bless {
+            fs::write(&out_path, &actual).unwrap();
+            continue;
+        }
+        let expected = read_expected(case);
+        if actual != expected {
+            failures.push(case.name);
+            eprintln!(
+                "{}: expected {:?}, got {:?}",
+                case.name,
+                String::from_utf8_lossy(&expected),
+                String::from_utf8_lossy(&actual),
+            );
+        }
+    }
+    assert!(failures.is_empty(), "failed cases: {:?}", failures);
+}
+
+#[test]
+fn test_minified_examples() {
+    let options = MinifyOptions {
+        inline: true,
+        rename: true,
+    };
+    // Minified programs take fewer steps, so programs that never end would
+    // stop at a different point.
+    for case in CASES.iter().filter(|case| case.max_steps.is_none()) {
+        let minified = metatape::minify(&load_example(case), &options);
+        let program = metatape::parse_str(&minified)
+            .unwrap_or_else(|errors| panic!("{}: {}", case.name, errors[0]));
+        assert!(
+            run_case(case, program) == read_expected(case),
+            "{}: minified program has different output",
+            case.name,
+        );
+    }
+}
+
+#[test]
+fn test_every_example_has_a_case() {
+    for entry in fs::read_dir("examples").unwrap() {
+        let file_name = entry.unwrap().file_name();
+        let file_name = file_name.to_str().unwrap();
+        assert!(
+            CASES.iter().any(|case| case.example == file_name),
+            "no test case for examples/{}",
+            file_name,
+        );
+    }
+}